@@ -1,132 +1,528 @@
-use std::collections::HashMap;
 use std::fs;
-use std::fs::DirEntry;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use serde_json::{json, Value};
+
+use crate::backend::{GpuBackend, GpuMetrics, Restorer};
+use crate::dry_run::{is_dry_run, write_sysfs};
+use crate::error::Error;
+use crate::logging::{error, warning};
+use crate::state::{clear_original_state, load_or_save_original_state};
 
 const HWMON_PATH: &str = "/sys/class/hwmon";
 
-/// Retrieves AMD GPU information and metrics.
-///
-/// This function serves as the primary entry point for obtaining AMD GPU data.
-/// It coordinates the following processes:
-/// 1. Locating the AMD GPU's hwmon directory
-/// 2. Gathering relevant file paths for metric collection
-/// 3. Calculating various metrics, including temperatures and fan speeds
+/// `pwm1_enable` value for manual fan control
+const PWM_ENABLE_MANUAL: &str = "1";
+/// `pwm1_enable` value for automatic fan control by the driver
+const PWM_ENABLE_AUTO: &str = "2";
+/// `errno` of a read from a sysfs file whose device was removed
+const ENODEV: i32 = 19;
+
+/// A single AMD GPU found through its amdgpu hwmon entry.
+#[derive(Clone, Debug)]
+pub struct AmdGpuCard {
+    /// Position of the card in the list sorted by PCI address
+    pub index: usize,
+    /// PCI slot of the card, e.g. `0000:03:00.0`
+    pub pci_address: String,
+    /// DRM card name of the card, e.g. `card1`
+    pub card_name: String,
+    hwmon: PathBuf,
+}
+
+impl AmdGpuCard {
+    /// Short human readable label used in the TUI and in error messages.
+    pub fn label(&self) -> String {
+        format!("{} ({})", self.card_name, self.pci_address)
+    }
+}
+
+/// Cached handles to the hwmon sensor files of an AMD GPU.
 ///
-/// # Returns
-/// - `Some(HashMap<&'static str, f32>)`: A collection of calculated metrics if successful
-/// - `None`: If any step in the process fails
-pub fn get_amdgpu_fan_metrics() -> Option<HashMap<&'static str, f32>> {
-    let hwmon = find_amdgpu_hwmon().expect("Failed to find amdgpu hwmon");
-    let amdgpu_paths = get_amdgpu_fan_info_paths(hwmon);
-    amdgpu_fan_calc(amdgpu_paths)
+/// Discovery of the hwmon directory and its sensor files is done once, after which
+/// each tick only re-reads the already open files with `pread`. Rediscovery is only
+/// triggered when the device vanished or no sensor could be read, for example after the
+/// amdgpu driver was reloaded.
+pub struct AmdGpuSensors {
+    card: AmdGpuCard,
+    hwmon: Option<PathBuf>,
+    files: Vec<(&'static str, File)>,
+    /// `pwm1_enable` as it was before we touched the fans
+    original_pwm_enable: String,
+    /// `pwm1` as it was before we touched the fans, only restored in manual mode
+    original_pwm: Option<String>,
+    /// `power1_cap` from before it was lowered, shared with the restorer
+    original_power_cap: Arc<Mutex<Option<String>>>,
+    /// Whether we switched `pwm1_enable` to manual and it should still be
+    manual: bool,
+    /// Whether the fans may be written at all, otherwise the card is only monitored
+    fan_control: bool,
+}
+
+impl AmdGpuSensors {
+    /// Snapshots `pwm1_enable` and `pwm1` so they can be restored on exit, or recovers the
+    /// snapshot a previous run left behind if it never got to restore it.
+    ///
+    /// Without `fan_control` (`--amd-fan-control`) the card is only monitored, nothing is
+    /// snapshotted and nothing is ever written to it.
+    pub fn new(card: AmdGpuCard, fan_control: bool) -> AmdGpuSensors {
+        if !fan_control {
+            return AmdGpuSensors { card, hwmon: None, files: Vec::with_capacity(6), original_pwm_enable: String::from(PWM_ENABLE_AUTO), original_pwm: None, original_power_cap: Arc::new(Mutex::new(None)), manual: false, fan_control };
+        }
+
+        let read = |file_name: &str| fs::read_to_string(card.hwmon.join(file_name)).ok().map(|value| value.trim().to_string());
+        let current = json!({ "pwm1_enable": read("pwm1_enable").unwrap_or_else(|| String::from(PWM_ENABLE_AUTO)), "pwm1": read("pwm1") });
+
+        let (original, recovered) = load_or_save_original_state(&format!("amdgpu-{}", card.pci_address), current);
+        let original_pwm_enable = original.get("pwm1_enable").and_then(Value::as_str).unwrap_or(PWM_ENABLE_AUTO).to_string();
+        let original_pwm = original.get("pwm1").and_then(Value::as_str).map(String::from);
+
+        if recovered {
+            warning!("The previous run did not restore the fans of {}, restoring the state it recorded", card.label());
+            restore_amdgpu_fan_state(&card, &original_pwm_enable, original_pwm.as_deref());
+        }
+
+        AmdGpuSensors { card, hwmon: None, files: Vec::with_capacity(6), original_pwm_enable, original_pwm, original_power_cap: Arc::new(Mutex::new(None)), manual: false, fan_control }
+    }
+
+    /// Retrieves AMD GPU information and metrics.
+    ///
+    /// This function serves as the primary entry point for obtaining AMD GPU data.
+    /// It coordinates the following processes:
+    /// 1. Locating the AMD GPU's hwmon directory (first call or after a failed read)
+    /// 2. Opening the relevant sensor files for metric collection
+    /// 3. Calculating various metrics, including temperatures and fan speeds
+    ///
+    /// # Errors
+    /// Fails if the hwmon directory is gone or none of the sensors could be read.
+    pub fn get_amdgpu_fan_metrics(&mut self) -> Result<GpuMetrics, Error> {
+        if self.hwmon.is_none() {
+            self.discover()?;
+        }
+
+        let readings = match self.read_sensors() {
+            Ok(readings) => readings,
+            Err(_) => {
+                // The cached handles went stale (driver reload, device reset), so look again
+                self.discover()?;
+                self.read_sensors().map_err(|e| Error::io(format!("Failed to read sensors of {}", self.card.label()), e))?
+            }
+        };
+
+        amdgpu_fan_calc(readings).ok_or_else(|| Error::Transient(format!("None of the sensors of {} reported a value", self.card.label())))
+    }
+
+    /// Locates the hwmon directory and opens every sensor file we care about.
+    ///
+    /// The hwmon number of a card can change when the driver is reloaded, so the
+    /// directory is looked up again by the card's PCI address.
+    fn discover(&mut self) -> Result<(), Error> {
+        self.hwmon = None;
+        self.files.clear();
+
+        // The card disappears for a moment while the driver is reloaded
+        let hwmon = find_amdgpu_hwmon(&self.card.pci_address).ok_or_else(|| Error::Transient(format!("Failed to find amdgpu hwmon for {}", self.card.label())))?;
+        self.files = open_amdgpu_fan_info_files(&hwmon);
+        self.hwmon = Some(hwmon);
+        Ok(())
+    }
+
+    /// Re-reads every cached sensor file from offset 0 without reopening it.
+    ///
+    /// Sensors that can't be read right now (e.g. `EINVAL` or `ENODATA` while the card
+    /// sleeps) are left out like missing ones. Only a vanished device, or no sensor
+    /// answering at all, is an error, which makes the caller look for the hwmon again.
+    fn read_sensors(&self) -> io::Result<Vec<(&'static str, String)>> {
+        let mut readings = Vec::with_capacity(self.files.len());
+        let mut buffer = [0u8; 32];
+        let mut last_error = None;
+
+        for (file_name, file) in &self.files {
+            match file.read_at(&mut buffer, 0) {
+                Ok(len) => readings.push((*file_name, String::from_utf8_lossy(&buffer[..len]).into_owned())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound || e.raw_os_error() == Some(ENODEV) => return Err(e),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        match last_error {
+            Some(e) if readings.is_empty() => Err(e),
+            _ => Ok(readings),
+        }
+    }
+
+    /// Writes a value to a file in the card's hwmon directory, such as `pwm1`.
+    fn write_hwmon_file(&mut self, file_name: &str, value: &str) -> Result<(), Error> {
+        if self.hwmon.is_none() {
+            self.discover()?;
+        }
+
+        let hwmon = self.hwmon.as_ref().ok_or_else(|| Error::Transient(format!("Failed to find amdgpu hwmon for {}", self.card.label())))?;
+        write_sysfs(&hwmon.join(file_name), value).map_err(|e| Error::io(format!("Failed to write {} for {}", file_name, self.card.label()), e))
+    }
 }
 
-/// Locates the hwmon directory for the AMD GPU.
+impl GpuBackend for AmdGpuSensors {
+    fn id(&self) -> String {
+        format!("amdgpu-{}", self.card.pci_address)
+    }
+
+    fn label(&self) -> String {
+        self.card.label()
+    }
+
+    fn read_metrics(&mut self) -> Result<GpuMetrics, Error> {
+        self.get_amdgpu_fan_metrics()
+    }
+
+    /// Switches `pwm1` to manual mode and scales the percentage to the 0-255 PWM range.
+    fn set_fan_speed(&mut self, speed: u8) -> Result<(), Error> {
+        if !self.fan_control {
+            return Err(Error::Unsupported(format!("Fan control of {} is off, enable it with --amd-fan-control", self.card.label())));
+        }
+        let pwm = (speed.min(100) as u32 * 255 / 100).to_string();
+        self.write_hwmon_file("pwm1_enable", PWM_ENABLE_MANUAL)?;
+        self.write_hwmon_file("pwm1", &pwm)?;
+        // Dry runs never switch to manual, so there is nothing to keep an eye on
+        self.manual = !is_dry_run();
+        Ok(())
+    }
+
+    /// Writes `power1_cap`, which amdgpu expects in microwatts.
+    fn set_power_limit(&mut self, watts: Option<u32>) -> Result<(), Error> {
+        if !self.fan_control {
+            return Err(Error::Unsupported(format!("Fan control of {} is off, enable it with --amd-fan-control", self.card.label())));
+        }
+        let original_power_cap = Arc::clone(&self.original_power_cap);
+        let mut original_power_cap = original_power_cap.lock().unwrap_or_else(PoisonError::into_inner);
+        match watts {
+            Some(watts) => {
+                if original_power_cap.is_none() {
+                    let hwmon = find_amdgpu_hwmon(&self.card.pci_address).ok_or_else(|| Error::Transient(format!("Failed to find amdgpu hwmon for {}", self.card.label())))?;
+                    *original_power_cap = Some(fs::read_to_string(hwmon.join("power1_cap")).map_err(|e| Error::io(format!("Failed to read power1_cap for {}", self.card.label()), e))?.trim().to_string());
+                }
+                self.write_hwmon_file("power1_cap", &(watts as u64 * 1_000_000).to_string())
+            }
+            None => match original_power_cap.clone() {
+                Some(power_cap) => self.write_hwmon_file("power1_cap", &power_cap).map(|_| *original_power_cap = None),
+                None => Ok(()),
+            },
+        }
+    }
+
+    fn controls_fans(&self) -> bool {
+        self.fan_control
+    }
+
+    /// Notices `pwm1_enable` leaving manual mode, which means someone else is controlling the fans.
+    fn control_lost(&mut self) -> Option<String> {
+        if !self.manual {
+            return None;
+        }
+        let pwm_enable = fs::read_to_string(self.hwmon.as_ref()?.join("pwm1_enable")).ok()?.trim().to_string();
+        if pwm_enable == PWM_ENABLE_MANUAL {
+            return None;
+        }
+        self.manual = false;
+        Some(format!("pwm1_enable was changed from {} to {}", PWM_ENABLE_MANUAL, pwm_enable))
+    }
+
+    fn pause(&mut self) {
+        if !self.fan_control {
+            return;
+        }
+        self.manual = false;
+        restore_amdgpu_fan_state(&self.card, &self.original_pwm_enable, self.original_pwm.as_deref());
+    }
+
+    /// Puts `pwm1_enable` (and `pwm1` in manual mode) back to what they were at startup,
+    /// along with `power1_cap` if it was lowered.
+    fn restorer(&self) -> Restorer {
+        if !self.fan_control {
+            return Arc::new(|| {});
+        }
+        let id = self.id();
+        let card = self.card.clone();
+        let original_pwm_enable = self.original_pwm_enable.clone();
+        let original_pwm = self.original_pwm.clone();
+        let original_power_cap = Arc::clone(&self.original_power_cap);
+        Arc::new(move || {
+            restore_amdgpu_fan_state(&card, &original_pwm_enable, original_pwm.as_deref());
+            clear_original_state(&id);
+
+            if let Some(power_cap) = original_power_cap.lock().unwrap_or_else(PoisonError::into_inner).as_ref() {
+                match find_amdgpu_hwmon(&card.pci_address) {
+                    Some(hwmon) => {
+                        if let Err(e) = write_sysfs(&hwmon.join("power1_cap"), power_cap) {
+                            error!("Failed to restore power1_cap for {}: {}", card.label(), e);
+                        }
+                    }
+                    None => error!("Failed to find amdgpu hwmon for {}", card.label()),
+                }
+            }
+        })
+    }
+}
+
+/// Writes a snapshot of the fan state back, looking the card up by PCI address since its
+/// hwmon directory may have changed in the meantime.
+fn restore_amdgpu_fan_state(card: &AmdGpuCard, pwm_enable: &str, pwm: Option<&str>) {
+    let Some(hwmon) = find_amdgpu_hwmon(&card.pci_address) else {
+        error!("Failed to find amdgpu hwmon for {}", card.label());
+        return;
+    };
+
+    // The duty cycle only sticks in manual mode, so it has to be written after switching back to it
+    if let Err(e) = write_sysfs(&hwmon.join("pwm1_enable"), pwm_enable) {
+        error!("Failed to restore pwm1_enable for {}: {}", card.label(), e);
+    }
+    if let (PWM_ENABLE_MANUAL, Some(pwm)) = (pwm_enable, pwm) {
+        if let Err(e) = write_sysfs(&hwmon.join("pwm1"), pwm) {
+            error!("Failed to restore pwm1 for {}: {}", card.label(), e);
+        }
+    }
+}
+
+/// Lists every AMD GPU that exposes an amdgpu hwmon directory.
 ///
-/// This function scans the HWMON_PATH directory to find the
-/// subdirectory associated with the AMD GPU's hardware monitoring.
-fn find_amdgpu_hwmon() -> Option<PathBuf> {
-    for entry in fs::read_dir(HWMON_PATH).unwrap() {
-        let entry = entry.unwrap();
+/// Cards are sorted by PCI address so that the index a user selects stays stable
+/// across reboots regardless of the order hwmon numbers were handed out in.
+pub fn enumerate_amdgpu_cards() -> Vec<AmdGpuCard> {
+    let mut cards = Vec::new();
+
+    let Ok(entries) = fs::read_dir(HWMON_PATH) else {
+        return cards;
+    };
+
+    for entry in entries {
+        let Ok(entry) = entry else { continue };
         let path = entry.path();
 
         if path.is_dir() {
             let name_file_path = path.join("name");
             if let Ok(name) = fs::read_to_string(name_file_path) {
                 if name.trim().contains("amdgpu") {
-                    return Some(path);
+                    let pci_address = pci_address_of(&path).unwrap_or_default();
+                    let card_name = drm_card_name_of(&path).unwrap_or_else(|| String::from("unknown"));
+                    cards.push(AmdGpuCard { index: 0, pci_address, card_name, hwmon: path });
                 }
             }
         }
     }
-    None
+
+    cards.sort_by(|a, b| a.pci_address.cmp(&b.pci_address));
+    for (index, card) in cards.iter_mut().enumerate() {
+        card.index = index;
+    }
+
+    cards
 }
 
-/// Retrieves file paths for AMD GPU metrics from the specified hwmon directory.
+/// Picks the cards matching a user supplied selector.
+///
+/// The selector can be `all`, an index into [`enumerate_amdgpu_cards`], a PCI address
+/// (the `0000:` domain prefix is optional) or a DRM card name such as `card1`.
+pub fn select_amdgpu_cards(cards: Vec<AmdGpuCard>, selector: &str) -> Result<Vec<AmdGpuCard>, Error> {
+    if cards.is_empty() {
+        return Err(Error::Unsupported(String::from("No amdgpu hwmon device found")));
+    }
+
+    let selector = selector.trim();
+    if selector.eq_ignore_ascii_case("all") {
+        return Ok(cards);
+    }
+
+    let selected: Vec<AmdGpuCard> = if let Ok(index) = selector.parse::<usize>() {
+        cards.into_iter().filter(|card| card.index == index).collect()
+    } else if selector.contains(':') {
+        cards.into_iter().filter(|card| card.pci_address == selector || card.pci_address.ends_with(&format!(":{}", selector))).collect()
+    } else {
+        cards.into_iter().filter(|card| card.card_name == selector).collect()
+    };
+
+    if selected.is_empty() {
+        Err(Error::Config(format!("No AMD card matches \"{}\" (use --list-amd-cards to see the available cards)", selector)))
+    } else {
+        Ok(selected)
+    }
+}
+
+/// Reads the sensors of a card once, without snapshotting or touching its fans.
+///
+/// Meant for one-off readings such as the status bar output, the control loop uses [`AmdGpuSensors`].
+///
+/// # Errors
+/// Fails if the hwmon directory is gone or none of the sensors could be read.
+pub fn sample_amdgpu_metrics(card: &AmdGpuCard) -> Result<GpuMetrics, Error> {
+    let hwmon = find_amdgpu_hwmon(&card.pci_address).ok_or_else(|| Error::Transient(format!("Failed to find amdgpu hwmon for {}", card.label())))?;
+    let mut buffer = [0u8; 32];
+    let readings = open_amdgpu_fan_info_files(&hwmon).iter().filter_map(|(file_name, file)| file.read_at(&mut buffer, 0).ok().map(|len| (*file_name, String::from_utf8_lossy(&buffer[..len]).into_owned()))).collect();
+    amdgpu_fan_calc(readings).ok_or_else(|| Error::Transient(format!("None of the sensors of {} reported a value", card.label())))
+}
+
+/// Locates the hwmon directory for the AMD GPU at the given PCI address.
+///
+/// This function scans the HWMON_PATH directory to find the
+/// subdirectory associated with the AMD GPU's hardware monitoring.
+fn find_amdgpu_hwmon(pci_address: &str) -> Option<PathBuf> {
+    enumerate_amdgpu_cards().into_iter().find(|card| card.pci_address == pci_address).map(|card| card.hwmon)
+}
+
+/// Resolves the PCI slot of a hwmon directory through its `device` symlink.
+fn pci_address_of(hwmon_path: &Path) -> Option<String> {
+    let device = fs::canonicalize(hwmon_path.join("device")).ok()?;
+    device.file_name()?.to_str().map(String::from)
+}
+
+/// Finds the DRM card (`cardN`) that belongs to the same PCI device as the hwmon directory.
+fn drm_card_name_of(hwmon_path: &Path) -> Option<String> {
+    fs::read_dir(hwmon_path.join("device").join("drm")).ok()?.filter_map(|entry| entry.ok()?.file_name().into_string().ok()).find(|name| {
+        name.strip_prefix("card").is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+    })
+}
+
+/// Opens the files for AMD GPU metrics from the specified hwmon directory.
 ///
 /// This function scans the given hwmon directory for specific files related to
-/// AMD GPU metrics, including fan speeds and temperatures. It returns a HashMap
-/// containing the relevant file paths if found.
-fn get_amdgpu_fan_info_paths(hwmon_path: PathBuf) -> Option<HashMap<&'static str, DirEntry>> {
-    let mut file_paths = HashMap::with_capacity(6);
+/// AMD GPU metrics, including fan speeds and temperatures. It returns the open
+/// file handles paired with the metric they provide.
+fn open_amdgpu_fan_info_files(hwmon_path: &Path) -> Vec<(&'static str, File)> {
+    let mut files = Vec::with_capacity(7);
 
-    for entry in fs::read_dir(hwmon_path).unwrap() {
-        let file = entry.unwrap();
+    let Ok(entries) = fs::read_dir(hwmon_path) else {
+        return files;
+    };
+
+    for entry in entries {
+        let Ok(file) = entry else { continue };
         let file_name = file.file_name();
         let file_name_str = file_name.to_str().unwrap_or("");
 
-        match file_name_str {
+        let metric = match file_name_str {
             s if s.starts_with("fan") => {
                 if s.ends_with("_min") {
-                    file_paths.insert("Min RPM", file);
+                    "Min RPM"
                 } else if s.ends_with("_max") {
-                    file_paths.insert("Max RPM", file);
+                    "Max RPM"
                 } else if s.ends_with("_input") {
-                    file_paths.insert("Current RPM", file);
+                    "Current RPM"
+                } else {
+                    continue;
                 }
             }
-            "temp1_input" => {
-                file_paths.insert("Edge Temp", file);
-            }
-            "temp2_input" => {
-                file_paths.insert("Junction Temp", file);
-            }
-            "temp3_input" => {
-                file_paths.insert("Memory Temp", file);
-            }
-            _ => {}
+            "pwm1" => "PWM Duty",
+            "temp1_input" => "Edge Temp",
+            "temp2_input" => "Junction Temp",
+            "temp3_input" => "Memory Temp",
+            _ => continue,
+        };
+
+        if files.iter().any(|(name, _)| *name == metric) {
+            continue;
         }
 
-        if file_paths.len() == 6 {
+        if let Ok(handle) = File::open(file.path()) {
+            files.push((metric, handle));
+        }
+
+        if files.len() == 7 {
             break;
         }
     }
 
-    Some(file_paths)
+    files
 }
 
-/// Calculates and processes AMD GPU metrics based on provided file paths.
+/// Calculates and processes AMD GPU metrics based on the raw sensor readings.
 ///
-/// This function takes a HashMap of file paths associated with various AMD GPU metrics,
-/// reads the contents of these files, and processes the data to produce a set of
+/// This function takes the contents read from the sensor files associated with the
+/// various AMD GPU metrics and processes the data to produce a set of
 /// human-readable metrics. It performs the following operations:
 ///
-/// 1. Reads and parses RPM values (minimum, maximum, and current)
+/// 1. Parses RPM values (minimum, maximum, and current)
 /// 2. Calculates fan speed percentage based on the RPM values
-/// 3. Reads and converts temperature values from millidegrees to degrees Celsius
+/// 3. Converts temperature values from millidegrees to degrees Celsius
+/// 4. Scales the `pwm1` duty cycle from 0-255 to percent
 ///
-/// The function returns a HashMap containing processed metrics if successful, or None if
-/// critical data is missing or cannot be parsed.
-fn amdgpu_fan_calc(amdgpu_paths: Option<HashMap<&str, DirEntry>>) -> Option<HashMap<&str, f32>> {
-    let mut result = HashMap::new();
+/// Cards without a fan (such as the iGPU of an APU) still report their temperatures,
+/// the RPM based values are only set when all three RPM values could be read.
+/// The function returns None if no metric at all could be parsed.
+fn amdgpu_fan_calc(readings: Vec<(&'static str, String)>) -> Option<GpuMetrics> {
+    let mut result = GpuMetrics::default();
     let mut rpm_values: [Option<f32>; 3] = [None; 3];
 
-    if let Some(paths) = amdgpu_paths {
-        for (file_name, dir_entry) in paths {
-            if let Ok(content) = fs::read_to_string(dir_entry.path()) {
-                match file_name {
-                    "Min RPM" => rpm_values[0] = content.trim().parse().ok(),
-                    "Max RPM" => rpm_values[1] = content.trim().parse().ok(),
-                    "Current RPM" => rpm_values[2] = content.trim().parse().ok(),
-                    "Edge Temp" | "Junction Temp" | "Memory Temp" => {
-                        if let Ok(temp_value) = content.trim().parse::<f32>() {
-                            result.insert(file_name, temp_value / 1000.0);
-                        }
-                    }
-                    _ => {}
-                }
-            }
+    for (file_name, content) in readings {
+        let value = content.trim().parse::<f32>().ok();
+        match file_name {
+            "Min RPM" => rpm_values[0] = value,
+            "Max RPM" => rpm_values[1] = value,
+            "Current RPM" => rpm_values[2] = value,
+            "Edge Temp" => result.temp = value.map(|temp_value| temp_value / 1000.0),
+            "Junction Temp" => result.junction_temp = value.map(|temp_value| temp_value / 1000.0),
+            "Memory Temp" => result.memory_temp = value.map(|temp_value| temp_value / 1000.0),
+            "PWM Duty" => result.fan_duty = value.map(|pwm| pwm / 255.0 * 100.0),
+            _ => {}
         }
-    } else {
-        return None;
     }
 
     if let [Some(min), Some(max), Some(current)] = rpm_values {
         let percentage = ((current - min) / (max - min)) * 100.0;
-        result.extend([("Min RPM", min), ("Max RPM", max), ("Current RPM", current), ("Fan Speed Percentage", percentage)]);
-        Some(result)
-    } else {
+        result.fan_min_rpm = Some(min);
+        result.fan_max_rpm = Some(max);
+        result.fan_rpm = Some(current);
+        result.fan_speed = Some(percentage);
+    }
+
+    if result.temp.is_none() && result.junction_temp.is_none() && result.memory_temp.is_none() && result.fan_rpm.is_none() {
         None
+    } else {
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// hwmon directory with a readable `temp1_input` and a `fan1_input` that fails every read.
+    fn fake_hwmon(name: &str) -> PathBuf {
+        let hwmon = std::env::temp_dir().join(format!("rust-gpu-fan-control-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&hwmon);
+        fs::create_dir_all(hwmon.join("fan1_input")).unwrap();
+        fs::write(hwmon.join("temp1_input"), "45000\n").unwrap();
+        hwmon
+    }
+
+    fn sensors(hwmon: &Path) -> AmdGpuSensors {
+        let card = AmdGpuCard { index: 0, pci_address: String::from("0000:03:00.0"), card_name: String::from("card1"), hwmon: hwmon.to_path_buf() };
+        let mut sensors = AmdGpuSensors::new(card, false);
+        sensors.files = open_amdgpu_fan_info_files(hwmon);
+        sensors
+    }
+
+    #[test]
+    fn unreadable_sensor_files_are_skipped() {
+        let hwmon = fake_hwmon("amd-skip");
+        let sensors = sensors(&hwmon);
+        assert_eq!(sensors.files.len(), 2);
+
+        // A directory fails every read with EISDIR, like a sensor answering EINVAL
+        let readings = sensors.read_sensors().unwrap();
+        assert_eq!(readings, [("Edge Temp", String::from("45000\n"))]);
+        assert_eq!(amdgpu_fan_calc(readings).and_then(|metrics| metrics.temp), Some(45.0));
+        fs::remove_dir_all(hwmon).unwrap();
+    }
+
+    #[test]
+    fn no_readable_sensor_is_an_error() {
+        let hwmon = fake_hwmon("amd-none");
+        let mut sensors = sensors(&hwmon);
+        sensors.files.retain(|(name, _)| *name == "Current RPM");
+        assert!(sensors.read_sensors().is_err());
+        fs::remove_dir_all(hwmon).unwrap();
     }
 }
@@ -0,0 +1,233 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::emergency::EmergencyState;
+use crate::error::Error;
+
+/// Hands fan control of one GPU back to the driver.
+///
+/// Restorers don't borrow their backend so they can run from a signal or panic handler
+/// while the control loop still holds the backend.
+pub type Restorer = Arc<dyn Fn() + Send + Sync>;
+
+/// Sensor values reported by a backend for a single tick.
+///
+/// Every value except the temperature driving the fan curve is optional since not
+/// every GPU (or driver) exposes the same set of sensors.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GpuMetrics {
+    /// Edge/core temperature in °C, this is what the fan curve reacts to
+    pub temp: Option<f32>,
+    /// Junction/hotspot temperature in °C
+    pub junction_temp: Option<f32>,
+    /// Memory/vram temperature in °C
+    pub memory_temp: Option<f32>,
+    /// Current fan RPM
+    pub fan_rpm: Option<f32>,
+    /// Minimum fan RPM the card supports
+    pub fan_min_rpm: Option<f32>,
+    /// Maximum fan RPM the card supports
+    pub fan_max_rpm: Option<f32>,
+    /// Fan speed in percent as reported by the card
+    pub fan_speed: Option<f32>,
+    /// PWM duty cycle in percent as read back from the card, what fan speed writes actually set
+    pub fan_duty: Option<f32>,
+}
+
+impl GpuMetrics {
+    /// Lists the sensors that did not report a value, using the names shown in warnings.
+    pub fn missing_sensors(&self) -> Vec<&'static str> {
+        [
+            ("Current RPM", self.fan_rpm),
+            ("Fan Speed Percentage", self.fan_speed),
+            ("temp", self.temp),
+            ("Junction Temp", self.junction_temp),
+            ("Memory Temp", self.memory_temp),
+            ("Min RPM", self.fan_min_rpm),
+            ("Max RPM", self.fan_max_rpm),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| if value.is_none() { Some(name) } else { None })
+        .collect()
+    }
+}
+
+/// Which backend drives the GPUs, picked by `--backend`, `--replay` or detected with `lspci`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    Nvidia,
+    Amd,
+    /// The simulated GPU of `--backend sim`
+    Sim,
+    /// A trace recorded with `--record`, played back by `--replay`
+    Replay,
+}
+
+impl BackendKind {
+    /// Parses a value of `--backend`, replays are only started with `--replay`.
+    pub fn parse(name: &str) -> Option<BackendKind> {
+        match name {
+            "nvidia" => Some(BackendKind::Nvidia),
+            "amd" => Some(BackendKind::Amd),
+            "sim" => Some(BackendKind::Sim),
+            _ => None,
+        }
+    }
+
+    /// Whether the backend talks to real hardware, which needs root.
+    pub fn is_hardware(self) -> bool {
+        matches!(self, BackendKind::Nvidia | BackendKind::Amd)
+    }
+}
+
+/// Common interface for everything the control loop can read temperatures from and
+/// write fan speeds to.
+pub trait GpuBackend: Send {
+    /// Stable identifier of the GPU used in recorded traces, e.g. `amdgpu-0000:03:00.0`.
+    fn id(&self) -> String;
+
+    /// Human readable name of the GPU shown in the TUI and in error messages.
+    fn label(&self) -> String;
+
+    /// Reads the current sensor values.
+    ///
+    /// # Errors
+    /// Fails if the GPU could not be read at all.
+    fn read_metrics(&mut self) -> Result<GpuMetrics, Error>;
+
+    /// Sets every fan of the GPU to the given speed in percent.
+    ///
+    /// # Errors
+    /// Returns why the speed could not be written.
+    fn set_fan_speed(&mut self, speed: u8) -> Result<(), Error>;
+
+    /// Lowers the power limit to the given watts, or restores the limit from before it was first lowered.
+    ///
+    /// # Errors
+    /// Returns why the limit could not be changed, including GPUs that don't support it.
+    fn set_power_limit(&mut self, _watts: Option<u32>) -> Result<(), Error> {
+        Err(Error::Unsupported(String::from("changing the power limit is not supported for this GPU")))
+    }
+
+    /// Returns what to run to hand fan control (and a lowered power limit) back to the driver when the program exits.
+    fn restorer(&self) -> Restorer;
+
+    /// Why fan control was taken away from us since the last write, e.g. by another fan controller.
+    fn control_lost(&mut self) -> Option<String> {
+        None
+    }
+
+    /// Hands fan control back to the driver while control is paused.
+    ///
+    /// Unlike the restorer this keeps the saved pre-launch state, which is still needed
+    /// once control resumes and the program eventually exits.
+    fn pause(&mut self) {
+        (self.restorer())();
+    }
+
+    /// Whether the control loop may write to the fans, GPUs that are only monitored are just read.
+    fn controls_fans(&self) -> bool {
+        true
+    }
+
+    /// Whether the reported fan speed is expected to follow the speeds written to it.
+    fn follows_writes(&self) -> bool {
+        true
+    }
+
+    /// Whether the backend has run out of data, only ever true for replayed traces.
+    fn finished(&self) -> bool {
+        false
+    }
+}
+
+/// What picked the fan speed of a readout, stored in traces, CSV logs and MQTT states.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpeedReason {
+    /// The fan curve of the active profile
+    Curve,
+    /// A speed set through the control socket
+    Override,
+    /// Readings that can't be trusted
+    Failsafe,
+    /// An emergency rule running the fans at 100%
+    Emergency,
+    /// Control is paused and the driver is in charge
+    Paused,
+    /// The GPU is only monitored, the speed is what the fans report
+    Monitor,
+}
+
+impl SpeedReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SpeedReason::Curve => "curve",
+            SpeedReason::Override => "override",
+            SpeedReason::Failsafe => "failsafe",
+            SpeedReason::Emergency => "emergency",
+            SpeedReason::Paused => "paused",
+            SpeedReason::Monitor => "monitor",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<SpeedReason> {
+        match name {
+            "curve" => Some(SpeedReason::Curve),
+            "override" => Some(SpeedReason::Override),
+            "failsafe" => Some(SpeedReason::Failsafe),
+            "emergency" => Some(SpeedReason::Emergency),
+            "paused" => Some(SpeedReason::Paused),
+            "monitor" => Some(SpeedReason::Monitor),
+            _ => None,
+        }
+    }
+}
+
+/// What happened to a single GPU during one iteration of the control loop.
+#[derive(Clone, Debug)]
+pub struct GpuReadout {
+    /// Unix time of the reading in seconds
+    pub timestamp: f64,
+    pub id: String,
+    pub label: String,
+    pub metrics: GpuMetrics,
+    /// Temperature the fan curve was evaluated for
+    pub temp: u8,
+    /// Speed the fan curve asked for
+    pub speed_output: u8,
+    /// Whether the speed was written to the fans this tick
+    pub changed: bool,
+    /// What picked the speed
+    pub reason: SpeedReason,
+    /// Why the failsafe speed is used instead of the fan curve, if it is
+    pub failsafe: Option<String>,
+    /// Emergency actions in effect
+    pub emergency: Vec<String>,
+}
+
+/// A GPU under control together with the state the control loop keeps for it.
+pub struct ControlledGpu {
+    pub backend: Box<dyn GpuBackend>,
+    /// Last speed written to the fans, `None` until the first write
+    pub last_speed: Option<u8>,
+    /// Why the failsafe speed is active, `None` while readings are good
+    pub failsafe: Option<String>,
+    /// When `last_speed` was written, the reported fan speed is only checked once it had time to settle
+    pub written_at: Option<Instant>,
+    /// Why the fans are not following the written speed, kept to only warn once
+    pub fan_mismatch: Option<String>,
+    /// Why the fans are considered stalled, kept to only alert once
+    pub fan_stall: Option<String>,
+    /// Progress of the emergency rules
+    pub emergency: EmergencyState,
+    /// Whether the last read failed, the driver may have reset the fans by the time reads work again
+    pub read_failed: bool,
+    /// Latest sensor values that differed from the ones before, and when they were read
+    pub last_change: Option<(GpuMetrics, Instant)>,
+}
+
+impl ControlledGpu {
+    pub fn new(backend: Box<dyn GpuBackend>) -> ControlledGpu {
+        ControlledGpu { backend, last_speed: None, failsafe: None, written_at: None, fan_mismatch: None, fan_stall: None, emergency: EmergencyState::default(), read_failed: false, last_change: None }
+    }
+}
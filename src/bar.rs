@@ -0,0 +1,122 @@
+use std::io::{self, IsTerminal};
+
+use owo_colors::OwoColorize;
+use serde_json::{json, Value};
+
+use crate::amdgpu::{enumerate_amdgpu_cards, sample_amdgpu_metrics, select_amdgpu_cards};
+use crate::backend::{BackendKind, GpuMetrics};
+use crate::calculations::{get_current_nvidia_fan_speed, get_current_nvidia_temp};
+use crate::colour_math::{rgb_temp, RgbColor};
+use crate::error::Error;
+use crate::trace::metrics_to_json;
+use crate::GPU_NUMBER;
+
+/// What the status line is printed for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BarFormat {
+    /// Plain text, coloured when printed to a terminal
+    Text,
+    /// JSON with `text`, `tooltip`, `class` and `percentage` for a Waybar custom module
+    Waybar,
+    /// Text with polybar's `%{F#rrggbb}` colour tags
+    Polybar,
+    /// The full text, short text and colour lines i3blocks reads
+    I3blocks,
+}
+
+impl BarFormat {
+    pub fn parse(name: &str) -> Option<BarFormat> {
+        match name {
+            "text" => Some(BarFormat::Text),
+            "waybar" => Some(BarFormat::Waybar),
+            "polybar" => Some(BarFormat::Polybar),
+            "i3blocks" => Some(BarFormat::I3blocks),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the GPUs directly when no daemon is running, without touching the fans.
+///
+/// Only real hardware can be sampled this way.
+///
+/// # Errors
+/// Fails if the GPU can't be read or is simulated or replayed.
+pub fn sample_gpus(backend_kind: BackendKind, amd_selector: &str) -> Result<Vec<Value>, Error> {
+    match backend_kind {
+        BackendKind::Nvidia => {
+            let metrics = GpuMetrics { temp: Some(get_current_nvidia_temp()? as f32), fan_speed: get_current_nvidia_fan_speed().map(|speed| speed as f32), ..Default::default() };
+            Ok(vec![metrics_to_json(&format!("nvidia-{}", GPU_NUMBER), &format!("NVIDIA gpu:{}", GPU_NUMBER), &metrics)])
+        }
+        BackendKind::Amd => select_amdgpu_cards(enumerate_amdgpu_cards(), amd_selector)?.iter().map(|card| Ok(metrics_to_json(&format!("amdgpu-{}", card.pci_address), &card.label(), &sample_amdgpu_metrics(card)?))).collect(),
+        BackendKind::Sim | BackendKind::Replay => Err(Error::Unsupported(String::from("Simulated and replayed GPUs only exist inside a running instance, start it with --daemon"))),
+    }
+}
+
+/// Class of a temperature for styling the bar, following the range of the colour gradient.
+fn temp_class(temp: f64) -> &'static str {
+    match temp {
+        temp if temp < 50.0 => "cool",
+        temp if temp < 70.0 => "warm",
+        temp if temp < 85.0 => "hot",
+        _ => "critical",
+    }
+}
+
+/// Formats the GPUs as one status line for `format`.
+///
+/// `status` is the daemon's answer to `status` when one is running, which adds the
+/// profile and control state, `None` when the GPUs were sampled directly.
+pub fn format_bar(gpus: &[Value], status: Option<&Value>, format: BarFormat) -> String {
+    let value = |gpu: &Value, key: &str| gpu.get(key).and_then(Value::as_f64);
+    let shown = |value: Option<f64>, unit: &str| value.map_or(String::from("?"), |value| format!("{:.0}{}", value, unit));
+
+    // The hottest GPU decides the colour, the busiest fan the percentage
+    let hottest = gpus.iter().filter_map(|gpu| value(gpu, "temp")).reduce(f64::max);
+    let fan_speed = gpus.iter().filter_map(|gpu| value(gpu, "fan_speed").or(value(gpu, "speed"))).reduce(f64::max);
+    let (r, g, b) = rgb_temp(&RgbColor::new(), hottest.unwrap_or(0.0).clamp(0.0, 255.0) as u8);
+    let colour = format!("#{:02x}{:02x}{:02x}", r, g, b);
+
+    let mut states = Vec::new();
+    let paused = status.and_then(|status| status.get("paused")).and_then(Value::as_bool) == Some(true);
+    if paused {
+        states.push("paused");
+    } else if status.and_then(|status| status.get("override")).is_some_and(|speed_override| !speed_override.is_null()) {
+        states.push("override");
+    }
+    if gpus.iter().any(|gpu| gpu.get("failsafe").is_some_and(|failsafe| !failsafe.is_null())) {
+        states.push("failsafe");
+    }
+    if gpus.iter().any(|gpu| gpu.get("emergency").and_then(Value::as_array).is_some_and(|emergency| !emergency.is_empty())) {
+        states.push("emergency");
+    }
+    let suffix = states.iter().map(|state| format!(" {}", state)).collect::<String>();
+
+    let temps = gpus.iter().map(|gpu| shown(value(gpu, "temp"), "°C")).collect::<Vec<_>>().join(" ");
+    let fans = gpus.iter().map(|gpu| shown(value(gpu, "fan_speed").or(value(gpu, "speed")), "%")).collect::<Vec<_>>().join(" ");
+    let text = format!("{} {}{}", temps, fans, suffix);
+
+    match format {
+        BarFormat::Text if io::stdout().is_terminal() => format!("{}{}\n", format!("{} {}", temps, fans).truecolor(r, g, b), suffix),
+        BarFormat::Text => format!("{}\n", text),
+        BarFormat::Polybar => format!("%{{F{}}}{}%{{F-}} {}{}\n", colour, temps, fans, suffix),
+        BarFormat::I3blocks => format!("{}\n{}\n{}\n", text, shown(hottest, "°C"), colour),
+        BarFormat::Waybar => {
+            let mut tooltip = Vec::new();
+            if let Some(profile) = status.and_then(|status| status.get("profile")).and_then(Value::as_str) {
+                tooltip.push(format!("Profile: {}{}", profile, suffix));
+            }
+            for gpu in gpus {
+                tooltip.push(gpu.get("label").and_then(Value::as_str).unwrap_or_default().to_string());
+                tooltip.push(format!("  Temp: {}, junction {}, memory {}", shown(value(gpu, "temp"), "°C"), shown(value(gpu, "junction_temp"), "°C"), shown(value(gpu, "memory_temp"), "°C")));
+                tooltip.push(format!("  Fans: {} at {}", shown(value(gpu, "fan_speed"), "%"), shown(value(gpu, "fan_rpm"), " RPM")));
+            }
+
+            let mut class = vec![hottest.map_or("unknown", temp_class)];
+            class.extend(states);
+            // Waybar renders the text as Pango markup
+            let text = format!("<span color=\"{}\">{}</span> {}{}", colour, temps, fans, suffix);
+            format!("{}\n", json!({ "text": text, "tooltip": tooltip.join("\n"), "class": class, "percentage": fan_speed.map_or(0, |speed| speed.round() as u64) }))
+        }
+    }
+}
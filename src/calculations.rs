@@ -1,25 +1,40 @@
-use crate::{GPU_NUMBER, SPEED};
-use std::io::Write;
-use std::process::{Command, Stdio};
+use crate::compile_flag_helper::FAN_AMOUNT;
+use crate::dry_run::{is_dry_run, log_dry_run};
+use crate::error::Error;
+use crate::logging::error;
+use crate::GPU_NUMBER;
+use std::io::{self, Read};
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long an NVIDIA tool gets to answer before it is considered hung and killed
+const NVIDIA_TOOL_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Additional increments applied to the default curve from the given temperature upwards.
+///
+/// Each entry is `(temperature, increment)`, the entry with the highest temperature
+/// that is still at or below the current temperature is applied.
+pub const HIGH_TEMP_INCREMENTS: [(u8, i8); 10] = [(70, 2), (72, 4), (74, 6), (76, -2), (78, 0), (79, 3), (80, 6), (82, 9), (84, 12), (85, 15)];
 
 /// Determine the appropriate fan speed based on the input temperature.
 ///
 /// This function performs two main tasks:
-/// 1. It finds the closest matching speed from a predefined set (SPEED) based on the input temperature.
-/// 2. It applies additional increments to the speed if the temperature is 70°C or higher.
+/// 1. It finds the closest matching speed from a set of speeds (such as SPEED) based on the input temperature.
+/// 2. It applies additional increments to the speed depending on the temperature.
 ///
 /// The function works as follows:
-/// - It iterates through the SPEED array to find the speed value closest to the input temperature.
-/// - If the temperature is 70°C or above, it applies an additional increment to the speed.
-///   The increment varies based on specific temperature ranges:
-///   - 70-71°C: +2, 72-73°C: +4, 74-77°C: +6, 78-79°C: +3, 80°C: +5,
-///   - 81°C: +10, 82°C: +12, 83°C: +14, 84°C: +16, 85°C and above: +15
+/// - It iterates through the speeds to find the speed value closest to the input temperature.
+/// - It then applies the increment of the highest entry in `increments` the temperature has reached.
+///   With the default HIGH_TEMP_INCREMENTS the increment varies based on specific temperature ranges:
+///   - 70-71°C: +2, 72-73°C: +4, 74-75°C: +6, 76-77°C: -2, 78°C: 0, 79°C: +3,
+///   - 80-81°C: +6, 82-83°C: +9, 84°C: +12, 85°C and above: +15
 /// - Finally, it ensures the output speed doesn't exceed 100 (maximum fan speed).
 ///
 /// This approach allows for fine-tuned fan speed control, with more aggressive cooling at higher temperatures.
-pub fn diff_func(temp: u8) -> u8 {
-    let (mut speed_output, _) = SPEED.iter().fold((0, u8::MAX), |(speed, min_diff), &x| {
-        let diff = if x > temp { x - temp } else { temp - x };
+pub fn curve_speed(speeds: &[u8], increments: &[(u8, i8)], temp: u8) -> u8 {
+    let (mut speed_output, _) = speeds.iter().fold((0, u8::MAX), |(speed, min_diff), &x| {
+        let diff = x.abs_diff(temp);
         if diff < min_diff {
             (x, diff)
         } else {
@@ -27,70 +42,199 @@ pub fn diff_func(temp: u8) -> u8 {
         }
     });
 
-    if temp >= 70 {
-        let increment: i8 = match temp {
-            70..=71 => 2,
-            72..=73 => 4,
-            74..=75 => 6,
-            76..=77 => -2,
-            78..=78 => 0,
-            79..=79 => 3,
-            80..=81 => 6,
-            82..=83 => 9,
-            84 => 12,
-            85.. => 15,
-            _ => 0,
-        };
-
-        speed_output = if increment < 0 { speed_output.saturating_sub(increment.abs() as u8) } else { speed_output.saturating_add(increment as u8) };
+    if let Some(&(_, increment)) = increments.iter().filter(|(from_temp, _)| temp >= *from_temp).max_by_key(|(from_temp, _)| *from_temp) {
+        speed_output = if increment < 0 { speed_output.saturating_sub(increment.unsigned_abs()) } else { speed_output.saturating_add(increment as u8) };
     }
 
     speed_output.min(100)
 }
 
+/// Runs a command like [`Command::output`], but kills it once it took longer than `timeout`.
+///
+/// A hung driver makes `nvidia-smi` and `nvidia-settings` block forever, which would
+/// stall the control loop before the failsafe ever gets a say.
+fn output_with_timeout(command: &mut Command, timeout: Duration) -> io::Result<Output> {
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+
+    // Drained in the background so a tool filling the pipe can't block on it
+    fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+        thread::spawn(move || {
+            let mut buffer = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut buffer);
+            }
+            buffer
+        })
+    }
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no answer within {}s", timeout.as_secs())));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    Ok(Output { status, stdout: stdout.join().unwrap_or_default(), stderr: stderr.join().unwrap_or_default() })
+}
+
+/// Runs one of the NVIDIA tools, a missing tool means the backend can't work at all.
+fn run_nvidia_tool(command: &mut Command, program: &str) -> Result<Output, Error> {
+    output_with_timeout(command, NVIDIA_TOOL_TIMEOUT).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => Error::Unsupported(format!("{} not found, is the NVIDIA driver installed?", program)),
+        _ => Error::io(format!("Failed to execute {}", program), e),
+    })
+}
+
 /// Utilize NVIDIA Management Library (NVML) via nvidia-smi command-line interface
 /// to retrieve the current GPU temperature. This approach leverages the C-based
 /// NVML library indirectly through the nvidia-smi tool, providing a reliable
 /// method to access GPU temperature data without direct NVML integration.
-pub fn get_current_nvidia_temp() -> u8 {
-    match Command::new("nvidia-smi").args(["--query-gpu=temperature.gpu", "--format=csv,noheader"]).output() {
-        Ok(output) => {
-            if let Ok(temp_str) = String::from_utf8(output.stdout) {
-                if let Ok(temp) = temp_str.trim().parse() {
-                    return temp;
-                }
-            }
-            eprintln!("Error: Failed to parse temperature");
-            std::process::exit(1);
-        }
-        Err(e) => {
-            eprintln!("Error: Failed to execute nvidia-smi: {}", e);
-            std::process::exit(1);
-        }
-    }
+///
+/// # Errors
+/// Fails when nvidia-smi can't run or its output can't be parsed, the caller decides
+/// whether to retry or use the failsafe speed.
+pub fn get_current_nvidia_temp() -> Result<u8, Error> {
+    let output = run_nvidia_tool(Command::new("nvidia-smi").args(["--query-gpu=temperature.gpu", "--format=csv,noheader", &format!("--id={}", GPU_NUMBER)]), "nvidia-smi")?;
+    let temp_str = String::from_utf8_lossy(&output.stdout);
+    temp_str.trim().parse().map_err(|_| Error::Transient(format!("Failed to parse temperature from nvidia-smi output \"{}\"", temp_str.trim())))
 }
 
-/// Resets the GPU fan control to automatic mode upon programmatic exit.
+/// Reads an integer attribute through `nvidia-settings -q`, e.g. `[gpu:0]/GPUFanControlState`.
+pub fn query_nvidia_attribute(attribute: &str) -> Option<u32> {
+    let output = output_with_timeout(Command::new("nvidia-settings").args(["-q", attribute, "-t"]), NVIDIA_TOOL_TIMEOUT).ok()?;
+    String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+}
+
+/// Assigns an attribute through `nvidia-settings -a`, e.g. `[gpu:0]/GPUFanControlState=1`.
 ///
-/// This function is designed to be called when the script exits, typically in response
-/// to a SIGINT signal (Ctrl + C). It performs the following actions:
-/// 1. Sets the GPU fan control state back to automatic mode.
-/// 2. Restores the cursor visibility in the terminal.
+/// `nvidia-settings` exits successfully for some failed assignments (for example without
+/// Coolbits), so its output is checked for errors as well.
 ///
-/// # Panics
+/// # Errors
+/// Returns what went wrong if the command could not run or reported an error.
+pub fn assign_nvidia_attribute(assignment: &str) -> Result<(), Error> {
+    if is_dry_run() {
+        log_dry_run(&format!("run nvidia-settings -a {}", assignment));
+        return Ok(());
+    }
+
+    let output = run_nvidia_tool(Command::new("nvidia-settings").arg("-a").arg(assignment), "nvidia-settings")?;
+
+    let messages = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    if let Some(error) = messages.lines().map(str::trim).find(|line| line.starts_with("ERROR")) {
+        return Err(Error::Transient(format!("nvidia-settings failed to set {}: {}", assignment, error)));
+    }
+    if !output.status.success() {
+        return Err(Error::Transient(format!("nvidia-settings failed to set {} ({})", assignment, output.status)));
+    }
+
+    Ok(())
+}
+
+/// Takes manual control of the GPU fans and sets every fan to the given speed through `nvidia-settings`.
+///
+/// Manual control is requested on every call since the driver drops it when it is
+/// reloaded or another program hands control back to it.
+pub fn set_nvidia_fan_speed(speed: u8) -> Result<(), Error> {
+    assign_nvidia_attribute(&format!("[gpu:{}]/GPUFanControlState=1", GPU_NUMBER))?;
+    for faninc in 0..FAN_AMOUNT {
+        assign_nvidia_attribute(&format!("GPUTargetFanSpeed[fan:{}]={}", faninc, speed))?;
+    }
+    Ok(())
+}
+
+/// Reads the current power limit in watts through `nvidia-smi`.
+pub fn get_nvidia_power_limit() -> Option<f32> {
+    let output = output_with_timeout(Command::new("nvidia-smi").args(["--query-gpu=power.limit", "--format=csv,noheader,nounits", &format!("--id={}", GPU_NUMBER)]), NVIDIA_TOOL_TIMEOUT).ok()?;
+    String::from_utf8(output.stdout).ok()?.trim().parse().ok()
+}
+
+/// Sets the power limit in watts through `nvidia-smi`.
+pub fn set_nvidia_power_limit(watts: f32) -> Result<(), Error> {
+    if is_dry_run() {
+        log_dry_run(&format!("run nvidia-smi -i {} -pl {:.2}", GPU_NUMBER, watts));
+        return Ok(());
+    }
+
+    let output = run_nvidia_tool(Command::new("nvidia-smi").args(["-i", &GPU_NUMBER.to_string(), "-pl", &format!("{:.2}", watts)]), "nvidia-smi")?;
+    if !output.status.success() {
+        return Err(Error::Transient(format!("nvidia-smi failed to set the power limit to {:.0}W: {}", watts, String::from_utf8_lossy(&output.stdout).trim())));
+    }
+    Ok(())
+}
+
+/// Reads back the speed the fans actually run at, the slowest fan if there are several.
+pub fn get_current_nvidia_fan_speed() -> Option<u32> {
+    (0..FAN_AMOUNT).filter_map(|faninc| query_nvidia_attribute(&format!("[fan:{}]/GPUCurrentFanSpeed", faninc))).min()
+}
+
+/// Resets the GPU fan control to automatic mode upon programmatic exit.
 ///
-/// This function will panic if the `nvidia-settings` command fails to execute.
+/// This function is designed to be called when the program exits, in response to a
+/// termination signal (Ctrl + C, SIGTERM, ...) or a panic. It sets the GPU fan control
+/// state back to automatic mode. Failures are only reported since there is nothing
+/// left to fall back to while exiting.
 pub fn cleanup_nvidia() {
     // Set GPU fan control to automatic mode
-    Command::new("nvidia-settings")
-        .arg("-a")
-        .arg(format!("[gpu:{}]/GPUFanControlState=0", GPU_NUMBER))
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status()
-        .expect("Failed to execute nvidia-settings command");
-
-    // Restore cursor visibility
-    print!("\x1B[?25h");
-    let _ = std::io::stdout().flush();
+    if let Err(e) = assign_nvidia_attribute(&format!("[gpu:{}]/GPUFanControlState=0", GPU_NUMBER)) {
+        error!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SPEED;
+
+    /// The fan curve as it was compiled in before profiles existed.
+    fn compiled_in_curve(temp: u8) -> u8 {
+        let (mut speed_output, _) = SPEED.iter().fold((0, u8::MAX), |(speed, min_diff), &x| {
+            let diff = x.abs_diff(temp);
+            if diff < min_diff {
+                (x, diff)
+            } else {
+                (speed, min_diff)
+            }
+        });
+
+        if temp >= 70 {
+            let increment: i8 = match temp {
+                70..=71 => 2,
+                72..=73 => 4,
+                74..=75 => 6,
+                76..=77 => -2,
+                78 => 0,
+                79 => 3,
+                80..=81 => 6,
+                82..=83 => 9,
+                84 => 12,
+                _ => 15,
+            };
+            speed_output = if increment < 0 { speed_output.saturating_sub(increment.unsigned_abs()) } else { speed_output.saturating_add(increment as u8) };
+        }
+
+        speed_output.min(100)
+    }
+
+    #[test]
+    fn default_curve_matches_the_compiled_in_one() {
+        for temp in 0..=u8::MAX {
+            assert_eq!(curve_speed(&SPEED, &HIGH_TEMP_INCREMENTS, temp), compiled_in_curve(temp), "at {}°C", temp);
+        }
+    }
+
+    #[test]
+    fn curve_speed_clamps_increments() {
+        assert_eq!(curve_speed(&[10, 100], &[(0, -50)], 10), 0);
+        assert_eq!(curve_speed(&[10, 100], &[(90, 50)], 100), 100);
+        assert_eq!(curve_speed(&[10, 100], &[], 40), 10);
+    }
 }
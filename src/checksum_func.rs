@@ -1,18 +1,13 @@
 use std::fs::File;
 use std::io::Read;
-use std::process::exit;
 
 use sha2::{Digest, Sha256};
 
-pub fn compute_file_sha256(file_path: &str) -> String {
+use crate::error::Error;
+
+pub fn compute_file_sha256(file_path: &str) -> Result<String, Error> {
     // Open the file
-    let mut file = match File::open(file_path) {
-        Ok(file) => file,
-        Err(err) => {
-            eprintln!("Error opening the file: {}", err);
-            exit(1);
-        }
-    };
+    let mut file = File::open(file_path).map_err(|e| Error::io(format!("Error opening the file {}", file_path), e))?;
 
     // Initialize SHA-256 hasher
     let mut hasher = Sha256::new();
@@ -20,13 +15,9 @@ pub fn compute_file_sha256(file_path: &str) -> String {
     // Read and update hasher in chunks
     let mut buffer = [0; 8192];
     loop {
-        match file.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(n) => hasher.update(&buffer[..n]),
-            Err(err) => {
-                eprintln!("Error reading the file: {}", err);
-                exit(1);
-            }
+        match file.read(&mut buffer).map_err(|e| Error::io(format!("Error reading the file {}", file_path), e))? {
+            0 => break,
+            n => hasher.update(&buffer[..n]),
         }
     }
 
@@ -36,5 +27,5 @@ pub fn compute_file_sha256(file_path: &str) -> String {
     // Convert the hash result to hexadecimal string
     let hash_string: String = result.iter().map(|byte| format!("{:02x}", byte)).collect();
 
-    hash_string
+    Ok(hash_string)
 }
@@ -0,0 +1,125 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::report::format_duration;
+
+/// Sends one request to the running daemon and waits for its response.
+///
+/// # Errors
+/// Fails if no daemon listens on `socket`, or it answered with an error.
+pub fn send_request(socket: &str, request: &Value) -> Result<Value, Error> {
+    let mut stream = UnixStream::connect(socket).map_err(|e| match e.kind() {
+        io::ErrorKind::PermissionDenied => Error::Permission(format!("No permission to use the control socket {}, run with sudo or join its group", socket)),
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => Error::Unsupported(format!("No running instance found on {}, start one with --daemon", socket)),
+        _ => Error::io(format!("Failed to connect to {}", socket), e),
+    })?;
+
+    writeln!(stream, "{}", request).map_err(|e| Error::io("Failed to send the request", e))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).map_err(|e| Error::io("Failed to read the response", e))?;
+    serde_json::from_str(&line).map_err(|e| Error::Transient(format!("Invalid response from the daemon: {}", e)))
+}
+
+/// Whether the daemon carried out the request, along with its reason if it didn't.
+pub fn check_response(response: &Value) -> Result<(), Error> {
+    if response.get("ok").and_then(Value::as_bool) == Some(true) {
+        Ok(())
+    } else {
+        Err(Error::Config(response.get("error").and_then(Value::as_str).unwrap_or("The daemon rejected the request").to_string()))
+    }
+}
+
+/// Parses a duration such as `90`, `30s`, `10m` or `1h` into seconds.
+pub fn parse_duration(text: &str) -> Result<f64, String> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| c.is_ascii_alphabetic()) {
+        Some(index) => text.split_at(index),
+        None => (text, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err(format!("Invalid duration \"{}\", use a number followed by s, m or h", text)),
+    };
+    number.parse::<f64>().ok().filter(|number| *number > 0.0).map(|number| number * multiplier).ok_or(format!("Invalid duration \"{}\", use a number followed by s, m or h", text))
+}
+
+/// Human readable version of the response to `request`.
+pub fn format_response(request: &Value, response: &Value) -> String {
+    let text = |value: &Value, key: &str| value.get(key).and_then(Value::as_str).unwrap_or_default().to_string();
+    match request.get("command").and_then(Value::as_str).unwrap_or_default() {
+        "status" => format_status(response),
+        "profiles" => {
+            let active = text(response, "profile");
+            let profiles = response.get("profiles").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str);
+            profiles.map(|name| format!("{} {}\n", if name == active { "*" } else { " " }, name)).collect()
+        }
+        "set_profile" => format!("Switched to the \"{}\" profile\n", text(request, "name")),
+        "override" => match request.get("duration").and_then(Value::as_f64) {
+            Some(duration) => format!("Fans overridden at {}% for {}\n", request.get("speed").and_then(Value::as_u64).unwrap_or(0), format_duration(duration)),
+            None => format!("Fans overridden at {}% until resumed\n", request.get("speed").and_then(Value::as_u64).unwrap_or(0)),
+        },
+        "pause" => String::from("Control paused, the driver is in charge of the fans unless the failsafe or an emergency needs them\n"),
+        "resume" => String::from("Control resumed, back to the fan curve\n"),
+        "reload" => format!("Config reloaded, using the \"{}\" profile\n", text(response, "profile")),
+        _ => format!("{}\n", response),
+    }
+}
+
+/// Human readable version of a `status` response.
+fn format_status(status: &Value) -> String {
+    let mut output = format!("Profile: {}\n", status.get("profile").and_then(Value::as_str).unwrap_or("unknown"));
+
+    if status.get("paused").and_then(Value::as_bool) == Some(true) {
+        output.push_str("Control: paused, the driver is in charge\n");
+    } else if let Some(speed_override) = status.get("override").filter(|speed_override| !speed_override.is_null()) {
+        let speed = speed_override.get("speed").and_then(Value::as_u64).unwrap_or(0);
+        match speed_override.get("remaining").and_then(Value::as_f64) {
+            Some(remaining) => output.push_str(&format!("Control: overridden at {}% for another {}\n", speed, format_duration(remaining))),
+            None => output.push_str(&format!("Control: overridden at {}% until resumed\n", speed)),
+        }
+    } else {
+        output.push_str("Control: fan curve\n");
+    }
+
+    for gpu in status.get("gpus").and_then(Value::as_array).into_iter().flatten() {
+        let value = |key: &str, unit: &str| gpu.get(key).and_then(Value::as_f64).map_or(String::from("unknown"), |value| format!("{:.0}{}", value, unit));
+        output.push_str(&format!("\n{}\n", gpu.get("label").and_then(Value::as_str).unwrap_or_default()));
+        output.push_str(&format!("  Temp: {}, junction {}, memory {}\n", value("temp", "°C"), value("junction_temp", "°C"), value("memory_temp", "°C")));
+        output.push_str(&format!("  Fans: {} at {} (set to {})\n", value("fan_speed", "%"), value("fan_rpm", " RPM"), value("speed", "%")));
+        if let Some(reason) = gpu.get("failsafe").and_then(Value::as_str) {
+            output.push_str(&format!("  FAILSAFE: {}\n", reason));
+        }
+        let emergency: Vec<&str> = gpu.get("emergency").and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_str).collect();
+        if !emergency.is_empty() {
+            output.push_str(&format!("  EMERGENCY: {}\n", emergency.join(", ")));
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_understands_units() {
+        assert_eq!(parse_duration("90"), Ok(90.0));
+        assert_eq!(parse_duration("30s"), Ok(30.0));
+        assert_eq!(parse_duration("10m"), Ok(600.0));
+        assert_eq!(parse_duration("1.5h"), Ok(5400.0));
+        assert_eq!(parse_duration(" 2m "), Ok(120.0));
+    }
+
+    #[test]
+    fn parse_duration_rejects_nonsense() {
+        for text in ["", "0", "-5s", "10d", "m", "ten", "5 m"] {
+            assert!(parse_duration(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use crate::logging::error;
+
 /// Calculates the RGB color value corresponding to a given temperature.
 pub fn rgb_temp(rgb: &RgbColor, temp: u8) -> (u8, u8, u8) {
     // Invoke the primary calculation function with a predefined temperature range
@@ -26,7 +28,7 @@ pub fn rgb_temp_f32(min: f32, max: f32, rgb: &RgbColor, temp: f32) -> (u8, u8, u
     match returned_temp {
         Some((r, g, b)) => (r, g, b),
         _none => {
-            eprintln!("Error: returned_temp is None");
+            error!("returned_temp is None");
 
             (0, 0, 0)
         }
@@ -84,11 +86,8 @@ impl RgbColor {
 
 /// Calculates the appropriate gradient index based on the input temperature mapped to the color array.
 fn calculate_gradient_index(temp: f32, min_val: f32, max_val: f32, total_gradients: f32) -> u8 {
-    // Validate that the temperature is within the specified range
-    if temp < min_val || temp > max_val {
-        eprintln!("Error: Temperature is out of the specified range.");
-        std::process::exit(1);
-    }
+    // Keep the temperature within the specified range, a wrong color is better than stopping fan control
+    let temp = temp.clamp(min_val, max_val);
 
     // Adjust temp to start from min_val
     let adjusted_temp = temp - min_val;
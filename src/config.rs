@@ -0,0 +1,195 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::conflicts::ConflictPolicy;
+use crate::csv_log::CsvLogPolicy;
+use crate::daemon::DaemonSettings;
+use crate::emergency::{emergency_rules_from_json, EmergencyRule};
+use crate::error::Error;
+use crate::failsafe::FailsafePolicy;
+use crate::mqtt::MqttSettings;
+use crate::profile::{builtin_profiles, Profile};
+use crate::retry::RetryPolicy;
+use crate::startup::StartupPolicy;
+
+/// Config file read when `--config` is not given, it is fine for it to not exist.
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/rust-gpu-fan-control.json";
+
+/// Settings read from the JSON config file.
+///
+/// ```json
+/// {
+///     "profile": "quiet",
+///     "profiles": {
+///         "silent": { "speeds": [10, 20, 30, 40, 50, 60, 70, 80, 90, 100], "increments": [[50, -10], [85, 15]] }
+///     },
+///     "failsafe": { "speed": 100, "stale_after": 5 },
+///     "emergency": [{ "action": "full_speed", "temp": 90, "sustained": 5 }],
+///     "retry": { "attempts": 3, "initial_delay": 0.05 },
+///     "startup": { "timeout": 120 },
+///     "conflicts": { "action": "refuse" },
+///     "csv_log": { "max_size": 10, "daily": true, "keep": 7 },
+///     "daemon": { "socket": "/run/rust-gpu-fan-control.sock", "group": "wheel" },
+///     "mqtt": { "host": "localhost", "username": "fan", "password": "secret" }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Name of the profile used at startup
+    pub profile: String,
+    /// Built in profiles followed by the ones from the config file
+    pub profiles: Vec<Profile>,
+    /// How to react to missing, implausible or stale readings
+    pub failsafe: FailsafePolicy,
+    /// Critical thresholds and what to do once they are crossed
+    pub emergency: Vec<EmergencyRule>,
+    /// How transient errors reading or writing a GPU are retried
+    pub retry: RetryPolicy,
+    /// How long to wait for the GPU driver at startup
+    pub startup: StartupPolicy,
+    /// What to do about other fan controllers
+    pub conflicts: ConflictPolicy,
+    /// When the `--log-csv` file is rotated
+    pub csv_log: CsvLogPolicy,
+    /// Control socket of the daemon mode
+    pub daemon: DaemonSettings,
+    /// Broker the readings are published to
+    pub mqtt: MqttSettings,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config { profile: String::from("default"), profiles: builtin_profiles(), failsafe: FailsafePolicy::default(), emergency: Vec::new(), retry: RetryPolicy::default(), startup: StartupPolicy::default(), conflicts: ConflictPolicy::default(), csv_log: CsvLogPolicy::default(), daemon: DaemonSettings::default(), mqtt: MqttSettings::default() }
+    }
+}
+
+impl Config {
+    /// Loads the config from `path`, or from [`DEFAULT_CONFIG_PATH`] if it exists.
+    ///
+    /// A missing file is only an error when the path was given explicitly.
+    pub fn load(path: Option<&str>) -> Result<Config, Error> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => DEFAULT_CONFIG_PATH,
+            None => return Ok(Config::default()),
+        };
+
+        let content = fs::read_to_string(path).map_err(|e| Error::Config(format!("Failed to read config file {}: {}", path, e)))?;
+        let json: Value = serde_json::from_str(&content).map_err(|e| Error::Config(format!("Failed to parse config file {}: {}", path, e)))?;
+        Config::from_json(&json).map_err(|e| Error::Config(format!("Invalid config file {}: {}", path, e)))
+    }
+
+    fn from_json(json: &Value) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        if let Some(profiles) = json.get("profiles") {
+            let profiles = profiles.as_object().ok_or("\"profiles\" must be an object")?;
+            for (name, value) in profiles {
+                let profile = Profile::from_json(name, value)?;
+                config.profiles.retain(|existing| existing.name != profile.name);
+                config.profiles.push(profile);
+            }
+        }
+
+        if let Some(profile) = json.get("profile") {
+            config.profile = profile.as_str().ok_or("\"profile\" must be a string")?.to_string();
+        }
+
+        if let Some(failsafe) = json.get("failsafe") {
+            config.failsafe = FailsafePolicy::from_json(failsafe)?;
+        }
+
+        if let Some(emergency) = json.get("emergency") {
+            config.emergency = emergency_rules_from_json(emergency)?;
+        }
+
+        if let Some(retry) = json.get("retry") {
+            config.retry = RetryPolicy::from_json(retry)?;
+        }
+
+        if let Some(startup) = json.get("startup") {
+            config.startup = StartupPolicy::from_json(startup)?;
+        }
+
+        if let Some(conflicts) = json.get("conflicts") {
+            config.conflicts = ConflictPolicy::from_json(conflicts)?;
+        }
+
+        if let Some(csv_log) = json.get("csv_log") {
+            config.csv_log = CsvLogPolicy::from_json(csv_log)?;
+        }
+
+        if let Some(daemon) = json.get("daemon") {
+            config.daemon = DaemonSettings::from_json(daemon)?;
+        }
+
+        if let Some(mqtt) = json.get("mqtt") {
+            config.mqtt = MqttSettings::from_json(mqtt)?;
+        }
+
+        if config.find_profile(&config.profile).is_none() {
+            return Err(format!("Unknown profile \"{}\"", config.profile));
+        }
+
+        Ok(config)
+    }
+
+    /// Looks up a profile by name.
+    pub fn find_profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// Comma separated names of every available profile, used in error messages.
+    pub fn profile_names(&self) -> String {
+        self.profiles.iter().map(|profile| profile.name.as_str()).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Reads `key` of the config object `section` with `parse`, `None` if the key is missing.
+///
+/// `expected` completes the error message for values `parse` rejects, e.g. "must be a string".
+pub fn typed_key<T>(value: &Value, section: &str, key: &str, expected: &str, parse: impl Fn(&Value) -> Option<T>) -> Result<Option<T>, String> {
+    value.get(key).map(|field| parse(field).ok_or(format!("\"{}.{}\" {}", section, key, expected))).transpose()
+}
+
+/// Reads `key` as a number.
+pub fn number_key(value: &Value, section: &str, key: &str) -> Result<Option<f32>, String> {
+    typed_key(value, section, key, "must be a number", |field| field.as_f64().map(|number| number as f32))
+}
+
+/// Reads `key` as a number that can't be negative, e.g. a delay.
+pub fn positive_number_key(value: &Value, section: &str, key: &str) -> Result<Option<f32>, String> {
+    typed_key(value, section, key, "must be a positive number", |field| field.as_f64().filter(|number| *number >= 0.0).map(|number| number as f32))
+}
+
+/// Reads `key` as a fan speed in percent.
+pub fn percent_key(value: &Value, section: &str, key: &str) -> Result<Option<u8>, String> {
+    typed_key(value, section, key, "must be a number from 0 to 100", |field| field.as_u64().filter(|percent| *percent <= 100).map(|percent| percent as u8))
+}
+
+/// Reads `key` as a string.
+pub fn string_key(value: &Value, section: &str, key: &str) -> Result<Option<String>, String> {
+    typed_key(value, section, key, "must be a string", |field| field.as_str().map(String::from))
+}
+
+/// Reads `key` as a boolean.
+pub fn bool_key(value: &Value, section: &str, key: &str) -> Result<Option<bool>, String> {
+    typed_key(value, section, key, "must be true or false", Value::as_bool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn typed_keys_name_the_offending_key() {
+        let failsafe = json!({ "speed": 120, "stale_after": 2.5, "failsafe_on_stall": "yes" });
+        assert_eq!(percent_key(&failsafe, "failsafe", "speed"), Err(String::from("\"failsafe.speed\" must be a number from 0 to 100")));
+        assert_eq!(number_key(&failsafe, "failsafe", "stale_after"), Ok(Some(2.5)));
+        assert_eq!(number_key(&failsafe, "failsafe", "max_temp"), Ok(None));
+        assert_eq!(bool_key(&failsafe, "failsafe", "failsafe_on_stall"), Err(String::from("\"failsafe.failsafe_on_stall\" must be true or false")));
+    }
+}
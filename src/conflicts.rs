@@ -0,0 +1,132 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::error::Error;
+use crate::logging::warning;
+
+/// Fan controllers that fight over the same fans, as `(process name, display name)`.
+const KNOWN_CONTROLLERS: [(&str, &str); 7] = [
+    ("fancontrol", "fancontrol"),
+    ("corectrl", "CoreCtrl"),
+    ("corectrl_helper", "CoreCtrl"),
+    ("gwe", "GreenWithEnvy"),
+    ("nvfancontrol", "nvfancontrol"),
+    ("lact", "LACT"),
+    ("lact-daemon", "LACT"),
+];
+
+/// What to do about other fan controllers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictAction {
+    /// Don't look for them at all
+    Ignore,
+    /// Print a warning and carry on
+    Warn,
+    /// Refuse to start while one is running
+    Refuse,
+}
+
+/// How to deal with other programs controlling the same fans.
+///
+/// Running processes are checked at startup, and on AMD cards `pwm1_enable` is checked
+/// every tick for changes made by someone else. Changes while running are always only
+/// warned about and undone, refusing only applies to starting up.
+#[derive(Clone, Debug)]
+pub struct ConflictPolicy {
+    pub action: ConflictAction,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> ConflictPolicy {
+        ConflictPolicy { action: ConflictAction::Warn }
+    }
+}
+
+impl ConflictPolicy {
+    /// Parses the `conflicts` object of the config file.
+    ///
+    /// ```json
+    /// "conflicts": { "action": "refuse" }
+    /// ```
+    pub fn from_json(value: &Value) -> Result<ConflictPolicy, String> {
+        let action = match value.get("action").map(|action| action.as_str()) {
+            None => ConflictAction::Warn,
+            Some(Some("ignore")) => ConflictAction::Ignore,
+            Some(Some("warn")) => ConflictAction::Warn,
+            Some(Some("refuse")) => ConflictAction::Refuse,
+            Some(_) => return Err(String::from("\"conflicts.action\" must be one of ignore, warn, refuse")),
+        };
+        Ok(ConflictPolicy { action })
+    }
+
+    /// Looks for other fan controllers before we take over the fans.
+    ///
+    /// `refuse` only warns in dry runs, since they don't touch the fans.
+    ///
+    /// # Errors
+    /// Fails if another fan controller runs and the policy is to refuse.
+    pub fn check_running_controllers(&self, dry_run: bool) -> Result<(), Error> {
+        if self.action == ConflictAction::Ignore {
+            return Ok(());
+        }
+
+        let found = find_running_controllers();
+        if found.is_empty() {
+            return Ok(());
+        }
+
+        let list = found.iter().map(|(pid, name)| format!("{} (PID {})", name, pid)).collect::<Vec<_>>().join(", ");
+        if self.action == ConflictAction::Refuse && !dry_run {
+            return Err(Error::Conflict(format!("Other fan control software is running: {}, stop it first or set \"conflicts.action\" to \"warn\"", list)));
+        }
+        warning!("Other fan control software is running: {}, it may fight over the fans", list);
+        Ok(())
+    }
+}
+
+/// Interpreters whose script, the first argument, is the program that actually runs.
+const INTERPRETERS: [&str; 3] = ["perl", "sh", "bash"];
+
+/// Whether `name` is an interpreter, `python3.12` and friends included.
+fn is_interpreter(name: &str) -> bool {
+    name.starts_with("python") || INTERPRETERS.contains(&name)
+}
+
+/// Names a process goes by: its `comm`, the file name of its executable and, when that is
+/// an interpreter such as in `python3 /usr/bin/gwe`, the file name of the script it runs.
+///
+/// Other arguments are left alone, `vim /etc/fancontrol` does not run fancontrol.
+fn process_names(proc_dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_to_string(proc_dir.join("comm")).map(|comm| vec![comm.trim().to_string()]).unwrap_or_default();
+    if let Ok(cmdline) = fs::read(proc_dir.join("cmdline")) {
+        let file_names: Vec<String> = cmdline.split(|byte| *byte == 0).take(2).filter_map(|arg| Path::new(&*String::from_utf8_lossy(arg)).file_name().map(|name| name.to_string_lossy().into_owned())).collect();
+        let runs_script = file_names.first().is_some_and(|program| is_interpreter(program));
+        names.extend(file_names.into_iter().take(if runs_script { 2 } else { 1 }));
+    }
+    names
+}
+
+/// Scans `/proc` for known fan controllers, returning their PID and display name.
+fn find_running_controllers() -> Vec<(String, &'static str)> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    let own_pid = std::process::id().to_string();
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let pid = entry.file_name().to_string_lossy().into_owned();
+        if !pid.chars().all(|c| c.is_ascii_digit()) || pid == own_pid {
+            continue;
+        }
+
+        let names = process_names(&entry.path());
+        if let Some((_, display_name)) = KNOWN_CONTROLLERS.iter().find(|(process_name, _)| names.iter().any(|name| name.eq_ignore_ascii_case(process_name))) {
+            found.push((pid, *display_name));
+        }
+    }
+    found.sort();
+    found
+}
@@ -0,0 +1,335 @@
+use std::time::Instant;
+
+use crate::backend::{ControlledGpu, GpuMetrics, GpuReadout, SpeedReason};
+use crate::config::Config;
+use crate::conflicts::ConflictAction;
+use crate::dry_run::is_dry_run;
+use crate::emergency::evaluate_emergency_rules;
+use crate::error::Error;
+use crate::logging::{critical, debug, error, notice, warning};
+use crate::profile::Profile;
+
+/// What every GPU of a tick shares, picked up from the control state before the GPUs are visited.
+pub struct Tick<'a> {
+    pub profile: &'a Profile,
+    /// Fixed speed set through the control socket, wins over the curve
+    pub speed_override: Option<u8>,
+    /// Whether the driver is in charge of the fans, see [`control_gpu`]
+    pub paused: bool,
+    /// Whether the system just resumed from suspend, which resets the fans to automatic
+    pub resumed: bool,
+}
+
+/// Picks the speed of one GPU from its latest reading and writes it if it changed.
+///
+/// `metrics` is what was read at `read_started`. Untrustworthy readings, stalled fans and
+/// fans not following the written speed lead to the failsafe speed, sustained critical
+/// temperatures to the emergency rules. While paused the fans are handed back to the
+/// driver unless the failsafe or an emergency needs them.
+///
+/// # Errors
+/// Fails if writing the speed failed fatally, fan control has to stop then.
+pub fn control_gpu(gpu: &mut ControlledGpu, config: &Config, tick: &Tick, metrics: Result<GpuMetrics, Error>, read_started: Instant, timestamp: f64) -> Result<GpuReadout, Error> {
+    let label = gpu.backend.label();
+    let monitor_only = !gpu.backend.controls_fans();
+
+    // A driver reload shows up as failed reads, once they work again the fans need to be taken over again
+    if metrics.is_err() {
+        gpu.read_failed = true;
+    } else if gpu.read_failed {
+        gpu.read_failed = false;
+        gpu.last_speed = None;
+        notice!(gpu = gpu.backend.id(); "{}: readings are back, re-applying fan control", label);
+    }
+    // Somebody else took the fans over, most likely another fan controller, so take them back
+    let control_lost = gpu.backend.control_lost();
+    if tick.resumed {
+        gpu.last_speed = None;
+    } else if let Some(reason) = control_lost.filter(|_| gpu.last_speed.is_some() && config.conflicts.action != ConflictAction::Ignore) {
+        warning!(gpu = gpu.backend.id(); "{}: {}, is other fan control software running? Taking control back", label, reason);
+        gpu.last_speed = None;
+    }
+
+    // A sensor stuck on the same values is as untrustworthy as one that stopped answering
+    let mut unchanged_for = 0.0;
+    if let Ok(current) = &metrics {
+        match gpu.last_change {
+            Some((previous, since)) if previous == *current => unchanged_for = since.elapsed().as_secs_f32(),
+            _ => gpu.last_change = Some((*current, read_started)),
+        }
+    }
+    let reading = config.failsafe.check_reading(metrics.as_ref(), read_started.elapsed().as_secs_f32(), unchanged_for);
+
+    // Stalled fans and fans that don't reach the speed written to them are reported
+    // once, the previous verdict stands while a new speed is still settling and dry runs
+    // have nothing to check since nothing was written
+    let (stall_check, fan_check) = match (gpu.last_speed, gpu.written_at) {
+        (Some(written_speed), Some(written_at)) if gpu.backend.follows_writes() && !is_dry_run() => {
+            if written_at.elapsed().as_secs_f32() >= config.failsafe.settle_after {
+                (config.failsafe.check_fan_stall(metrics.as_ref().ok(), written_speed), config.failsafe.check_fan_speed(metrics.as_ref().ok(), written_speed))
+            } else {
+                (gpu.fan_stall.clone().map_or(Ok(()), Err), gpu.fan_mismatch.clone().map_or(Ok(()), Err))
+            }
+        }
+        _ => (Ok(()), Ok(())),
+    };
+    match &stall_check {
+        Ok(()) => {
+            if gpu.fan_stall.take().is_some() {
+                notice!(gpu = gpu.backend.id(); "{}: fans are spinning again", label);
+            }
+        }
+        Err(stall) => {
+            if gpu.fan_stall.is_none() {
+                critical!(gpu = gpu.backend.id(); "FAN STALL {}: {}", label, stall);
+            }
+            gpu.fan_stall = Some(stall.clone());
+        }
+    }
+    match &fan_check {
+        Ok(()) => {
+            if gpu.fan_mismatch.take().is_some() && gpu.fan_stall.is_none() {
+                notice!(gpu = gpu.backend.id(); "{}: fans follow the written speed again", label);
+            }
+        }
+        Err(mismatch) => {
+            // A stall already says everything there is to say
+            if gpu.fan_mismatch.is_none() && gpu.fan_stall.is_none() {
+                warning!(gpu = gpu.backend.id(); "{}: {}", label, mismatch);
+            }
+            gpu.fan_mismatch = Some(mismatch.clone());
+        }
+    }
+
+    // Readings we can't trust send the fans to the failsafe speed until good ones return
+    let reading = if config.failsafe.failsafe_on_stall { reading.and_then(|temp| stall_check.map(|_| temp)) } else { reading };
+    let reading = if config.failsafe.failsafe_on_mismatch { reading.and_then(|temp| fan_check.map(|_| temp)) } else { reading };
+    let mut picked_by = if tick.speed_override.is_some() { SpeedReason::Override } else { SpeedReason::Curve };
+    let speed_output = match reading {
+        // The driver is in charge of monitored GPUs, so there is nothing to fail safe to
+        _ if monitor_only => {
+            picked_by = SpeedReason::Monitor;
+            metrics.as_ref().ok().and_then(|metrics| metrics.fan_speed).map_or(0, |speed| speed.round() as u8)
+        }
+        Ok(temp) => {
+            if let Some(reason) = gpu.failsafe.take() {
+                notice!(gpu = gpu.backend.id(); "{}: readings are back ({}), leaving failsafe mode", label, reason);
+            }
+            tick.speed_override.unwrap_or_else(|| tick.profile.speed_for(temp as u8))
+        }
+        Err(reason) => {
+            // Only announce entering it, reasons such as the age of a reading change every tick
+            if gpu.failsafe.is_none() {
+                critical!(gpu = gpu.backend.id(), speed = config.failsafe.speed; "FAILSAFE {}: {}, setting fans to {}%", label, reason, config.failsafe.speed);
+            }
+            gpu.failsafe = Some(reason);
+            picked_by = SpeedReason::Failsafe;
+            config.failsafe.speed
+        }
+    };
+
+    // Sustained critical temperatures override both the curve and the failsafe
+    if !monitor_only {
+        evaluate_emergency_rules(&config.emergency, &mut gpu.emergency, gpu.backend.as_mut(), metrics.as_ref().ok());
+    }
+    let speed_output = if gpu.emergency.full_speed(&config.emergency) {
+        picked_by = SpeedReason::Emergency;
+        100
+    } else {
+        speed_output
+    };
+
+    // While paused the driver is in charge, unless the failsafe or an emergency needs the fans
+    let hands_off = tick.paused && !monitor_only && picked_by != SpeedReason::Failsafe && picked_by != SpeedReason::Emergency;
+    if hands_off {
+        picked_by = SpeedReason::Paused;
+        if gpu.last_speed.is_some() {
+            gpu.backend.pause();
+            gpu.last_speed = None;
+            gpu.written_at = None;
+        }
+    }
+
+    let metrics = metrics.unwrap_or_default();
+    let temp = metrics.temp.map_or(0, |value| value as u8);
+    let mut changed = !hands_off && !monitor_only && gpu.last_speed != Some(speed_output);
+    if changed {
+        match config.retry.run(|| gpu.backend.set_fan_speed(speed_output)) {
+            Ok(()) => {
+                debug!(gpu = gpu.backend.id(), speed = speed_output; "{}: set fans to {}% ({})", label, speed_output, picked_by.as_str());
+                gpu.last_speed = Some(speed_output);
+                gpu.written_at = Some(Instant::now());
+            }
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(e) => {
+                // Forget the last speed so the write is retried next tick
+                error!("{}", e);
+                changed = false;
+                gpu.last_speed = None;
+                gpu.written_at = None;
+            }
+        }
+    }
+
+    Ok(GpuReadout { timestamp, id: gpu.backend.id(), label, metrics, temp, speed_output, changed, reason: picked_by, failsafe: gpu.failsafe.clone(), emergency: gpu.emergency.active_actions(&config.emergency) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{parse_load_profile, SimulatedGpu};
+    use std::thread;
+    use std::time::Duration;
+
+    fn simulated_gpu() -> ControlledGpu {
+        ControlledGpu::new(Box::new(SimulatedGpu::new(parse_load_profile("gaming").unwrap(), 25.0, 1.0, 1.0)))
+    }
+
+    fn tick(profile: &Profile, paused: bool) -> Tick<'_> {
+        Tick { profile, speed_override: None, paused, resumed: false }
+    }
+
+    /// Reads the GPU and runs one tick of the control loop on it.
+    fn step(gpu: &mut ControlledGpu, config: &Config, tick: &Tick) -> GpuReadout {
+        let read_started = Instant::now();
+        let metrics = gpu.backend.read_metrics();
+        control_gpu(gpu, config, tick, metrics, read_started, 0.0).unwrap()
+    }
+
+    /// Runs one tick with a failed read, as if the driver stopped answering.
+    fn failed_step(gpu: &mut ControlledGpu, config: &Config, tick: &Tick) -> GpuReadout {
+        control_gpu(gpu, config, tick, Err(Error::Transient(String::from("nvidia-smi failed"))), Instant::now(), 0.0).unwrap()
+    }
+
+    #[test]
+    fn follows_the_curve() {
+        let config = Config::default();
+        let profile = Profile::default();
+        let mut gpu = simulated_gpu();
+
+        let readout = step(&mut gpu, &config, &tick(&profile, false));
+        assert_eq!(readout.reason, SpeedReason::Curve);
+        assert_eq!(readout.speed_output, profile.speed_for(readout.temp));
+        assert!(readout.changed);
+        assert_eq!(gpu.last_speed, Some(readout.speed_output));
+    }
+
+    #[test]
+    fn failed_readings_use_the_failsafe_speed_until_they_recover() {
+        let mut config = Config::default();
+        config.failsafe.speed = 90;
+        let profile = Profile::default();
+        let mut gpu = simulated_gpu();
+
+        let readout = failed_step(&mut gpu, &config, &tick(&profile, false));
+        assert_eq!(readout.reason, SpeedReason::Failsafe);
+        assert_eq!(readout.speed_output, 90);
+        assert!(readout.changed);
+        assert!(readout.failsafe.is_some_and(|reason| reason.contains("nvidia-smi failed")));
+        assert_eq!(gpu.last_speed, Some(90));
+
+        // Staying in failsafe mode doesn't write the same speed again
+        assert!(!failed_step(&mut gpu, &config, &tick(&profile, false)).changed);
+
+        let readout = step(&mut gpu, &config, &tick(&profile, false));
+        assert_eq!(readout.reason, SpeedReason::Curve);
+        assert_eq!(readout.failsafe, None);
+        assert_eq!(gpu.last_speed, Some(profile.speed_for(readout.temp)));
+    }
+
+    #[test]
+    fn stalled_fans_are_only_checked_once_they_had_time_to_settle() {
+        let mut config = Config::default();
+        config.failsafe.settle_after = 0.05;
+        let profile = Profile::default();
+        let tick = Tick { profile: &profile, speed_override: Some(60), paused: false, resumed: false };
+        let mut gpu = simulated_gpu();
+
+        let stalled = GpuMetrics { temp: Some(60.0), fan_rpm: Some(0.0), ..Default::default() };
+        assert_eq!(control_gpu(&mut gpu, &config, &tick, Ok(stalled), Instant::now(), 0.0).unwrap().reason, SpeedReason::Override);
+        assert_eq!(control_gpu(&mut gpu, &config, &tick, Ok(stalled), Instant::now(), 0.0).unwrap().reason, SpeedReason::Override);
+
+        thread::sleep(Duration::from_millis(60));
+        let readout = control_gpu(&mut gpu, &config, &tick, Ok(stalled), Instant::now(), 0.0).unwrap();
+        assert_eq!(readout.reason, SpeedReason::Failsafe);
+        assert_eq!(readout.speed_output, config.failsafe.speed);
+        assert!(gpu.fan_stall.is_some());
+
+        // The failsafe speed needs to settle as well, the stall stands until then
+        let spinning = GpuMetrics { fan_rpm: Some(2000.0), ..stalled };
+        assert_eq!(control_gpu(&mut gpu, &config, &tick, Ok(spinning), Instant::now(), 0.0).unwrap().reason, SpeedReason::Failsafe);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(control_gpu(&mut gpu, &config, &tick, Ok(spinning), Instant::now(), 0.0).unwrap().reason, SpeedReason::Override);
+        assert!(gpu.fan_stall.is_none());
+    }
+
+    #[test]
+    fn implausible_and_frozen_readings_trigger_the_failsafe() {
+        let mut config = Config::default();
+        config.failsafe.frozen_after = 0.05;
+        let profile = Profile::default();
+        let mut gpu = simulated_gpu();
+
+        let too_hot = GpuMetrics { temp: Some(180.0), ..Default::default() };
+        let readout = control_gpu(&mut gpu, &config, &tick(&profile, false), Ok(too_hot), Instant::now(), 0.0).unwrap();
+        assert_eq!(readout.reason, SpeedReason::Failsafe);
+        assert_eq!(readout.speed_output, config.failsafe.speed);
+
+        let stuck = GpuMetrics { temp: Some(60.0), ..Default::default() };
+        let mut gpu = simulated_gpu();
+        assert_eq!(control_gpu(&mut gpu, &config, &tick(&profile, false), Ok(stuck), Instant::now(), 0.0).unwrap().reason, SpeedReason::Curve);
+        thread::sleep(Duration::from_millis(100));
+        let readout = control_gpu(&mut gpu, &config, &tick(&profile, false), Ok(stuck), Instant::now(), 0.0).unwrap();
+        assert_eq!(readout.reason, SpeedReason::Failsafe);
+        assert!(readout.failsafe.is_some_and(|reason| reason.contains("same values")));
+    }
+
+    #[test]
+    fn idle_nvidia_readings_are_not_frozen_by_default() {
+        let config = Config::default();
+        let profile = Profile::default();
+        let mut gpu = simulated_gpu();
+
+        // Whole degrees and fan percent are all nvidia-smi reports, an idle card holds them for ages
+        let idle = GpuMetrics { temp: Some(38.0), fan_speed: Some(30.0), ..Default::default() };
+        gpu.last_change = Some((idle, Instant::now() - Duration::from_secs(3600)));
+        let readout = control_gpu(&mut gpu, &config, &tick(&profile, false), Ok(idle), Instant::now(), 0.0).unwrap();
+        assert_eq!(readout.reason, SpeedReason::Curve);
+        assert_eq!(readout.failsafe, None);
+    }
+
+    #[test]
+    fn pause_hands_the_fans_back_except_for_the_failsafe() {
+        let config = Config::default();
+        let profile = Profile::default();
+        let mut gpu = simulated_gpu();
+        step(&mut gpu, &config, &tick(&profile, false));
+
+        let readout = step(&mut gpu, &config, &tick(&profile, true));
+        assert_eq!(readout.reason, SpeedReason::Paused);
+        assert!(!readout.changed);
+        assert_eq!(gpu.last_speed, None);
+
+        let readout = failed_step(&mut gpu, &config, &tick(&profile, true));
+        assert_eq!(readout.reason, SpeedReason::Failsafe);
+        assert!(readout.changed);
+        assert_eq!(gpu.last_speed, Some(config.failsafe.speed));
+
+        // Once the readings are back the driver gets the fans again
+        let readout = step(&mut gpu, &config, &tick(&profile, true));
+        assert_eq!(readout.reason, SpeedReason::Paused);
+        assert_eq!(gpu.last_speed, None);
+    }
+
+    #[test]
+    fn override_wins_over_the_curve() {
+        let config = Config::default();
+        let profile = Profile::default();
+        let mut gpu = simulated_gpu();
+
+        let readout = step(&mut gpu, &config, &Tick { profile: &profile, speed_override: Some(65), paused: false, resumed: false });
+        assert_eq!(readout.reason, SpeedReason::Override);
+        assert_eq!(readout.speed_output, 65);
+        assert_eq!(gpu.last_speed, Some(65));
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::backend::GpuReadout;
+use crate::config::{bool_key, typed_key};
+use crate::error::Error;
+use crate::logging::error;
+use crate::trace::unix_timestamp;
+
+/// Column names, in the order `CsvLogger::log` writes them.
+const CSV_HEADER: &str = "time,timestamp,gpu,label,temp,junction_temp,memory_temp,fan_rpm,fan_speed,speed,wrote,reason,failsafe,emergency";
+
+/// When the CSV log is rotated and how many old files are kept.
+///
+/// Once the file grew past `max_size` megabytes, or with `daily` once the UTC day
+/// changed, it is renamed to `<path>.1`, older files move up to `<path>.2` and so on,
+/// and everything past `keep` old files is removed. A `max_size` of 0 never rotates by size.
+#[derive(Clone, Debug)]
+pub struct CsvLogPolicy {
+    pub max_size: f64,
+    pub daily: bool,
+    pub keep: u32,
+}
+
+impl Default for CsvLogPolicy {
+    fn default() -> CsvLogPolicy {
+        CsvLogPolicy { max_size: 10.0, daily: false, keep: 5 }
+    }
+}
+
+impl CsvLogPolicy {
+    /// Reads the rotation settings from the `csv_log` object of the config file.
+    ///
+    /// ```json
+    /// "csv_log": { "max_size": 10, "daily": true, "keep": 7 }
+    /// ```
+    pub fn from_json(value: &Value) -> Result<CsvLogPolicy, String> {
+        let mut policy = CsvLogPolicy::default();
+
+        if let Some(max_size) = typed_key(value, "csv_log", "max_size", "must be a positive number of megabytes", |max_size| max_size.as_f64().filter(|max_size| *max_size >= 0.0))? {
+            policy.max_size = max_size;
+        }
+        if let Some(daily) = bool_key(value, "csv_log", "daily")? {
+            policy.daily = daily;
+        }
+        if let Some(keep) = typed_key(value, "csv_log", "keep", "must be a positive number", Value::as_u64)? {
+            policy.keep = keep as u32;
+        }
+
+        Ok(policy)
+    }
+}
+
+/// Days since the Unix epoch of a Unix timestamp, the UTC day used for daily rotation.
+fn unix_day(timestamp: f64) -> i64 {
+    (timestamp / 86400.0).floor() as i64
+}
+
+/// Formats a Unix timestamp as an ISO 8601 UTC time spreadsheets understand, e.g. `2024-05-01T13:37:00Z`.
+fn format_utc(timestamp: f64) -> String {
+    let days = unix_day(timestamp);
+    let seconds = (timestamp - days as f64 * 86400.0) as u32;
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, seconds / 3600, seconds % 3600 / 60, seconds % 60)
+}
+
+/// Quotes a field if it contains anything that would break the CSV row.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Appends every tick's readouts to a CSV file, rotating it as configured.
+pub struct CsvLogger {
+    path: PathBuf,
+    policy: CsvLogPolicy,
+    writer: LineWriter<File>,
+    /// Bytes in the current file
+    size: u64,
+    /// UTC day the current file was started on
+    day: i64,
+}
+
+impl CsvLogger {
+    /// Opens the CSV log for appending, writing the header if the file is new.
+    ///
+    /// # Errors
+    /// Fails if the file can't be opened.
+    pub fn create(path: &str, policy: CsvLogPolicy) -> Result<CsvLogger, Error> {
+        let path = PathBuf::from(path);
+        let (writer, size) = CsvLogger::open(&path)?;
+        // Continuing an existing file, it was started on the day it was last changed
+        let day = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok().and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok()).map_or(unix_day(unix_timestamp()), |since| unix_day(since.as_secs_f64()));
+        Ok(CsvLogger { path, policy, writer, size, day })
+    }
+
+    fn open(path: &PathBuf) -> Result<(LineWriter<File>, u64), Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| Error::io(format!("Failed to open CSV log {}", path.display()), e))?;
+        let mut size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        let mut writer = LineWriter::new(file);
+        if size == 0 {
+            writeln!(writer, "{}", CSV_HEADER).map_err(|e| Error::io(format!("Failed to write to CSV log {}", path.display()), e))?;
+            size = CSV_HEADER.len() as u64 + 1;
+        }
+        Ok((writer, size))
+    }
+
+    /// Path of the `index`th old file, `<path>.1` being the newest.
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    /// Moves the current file out of the way and starts a new one.
+    fn rotate(&mut self, now: f64) -> Result<(), Error> {
+        let _ = fs::remove_file(self.rotated_path(self.policy.keep.max(1)));
+        for index in (1..self.policy.keep).rev() {
+            let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+        }
+        if self.policy.keep == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            fs::rename(&self.path, self.rotated_path(1)).map_err(|e| Error::io(format!("Failed to rotate CSV log {}", self.path.display()), e))?;
+        }
+
+        (self.writer, self.size) = CsvLogger::open(&self.path)?;
+        self.day = unix_day(now);
+        Ok(())
+    }
+
+    /// Writes one row per readout, rotating first if the file is due.
+    pub fn log(&mut self, readouts: &[GpuReadout]) {
+        let Some(now) = readouts.first().map(|readout| readout.timestamp) else { return };
+
+        let too_big = self.policy.max_size > 0.0 && self.size as f64 >= self.policy.max_size * 1_000_000.0;
+        let new_day = self.policy.daily && unix_day(now) != self.day;
+        if too_big || new_day {
+            if let Err(e) = self.rotate(now) {
+                error!("{}", e);
+            }
+        }
+
+        for readout in readouts {
+            let metrics = &readout.metrics;
+            let value = |value: Option<f32>| value.map_or(String::new(), |value| format!("{:.2}", value));
+            let row = [
+                format_utc(readout.timestamp),
+                format!("{:.3}", readout.timestamp),
+                csv_field(&readout.id),
+                csv_field(&readout.label),
+                value(metrics.temp),
+                value(metrics.junction_temp),
+                value(metrics.memory_temp),
+                value(metrics.fan_rpm),
+                value(metrics.fan_speed),
+                readout.speed_output.to_string(),
+                readout.changed.to_string(),
+                csv_field(readout.reason.as_str()),
+                csv_field(readout.failsafe.as_deref().unwrap_or_default()),
+                csv_field(&readout.emergency.join(";")),
+            ]
+            .join(",");
+
+            match writeln!(self.writer, "{}", row) {
+                Ok(()) => self.size += row.len() as u64 + 1,
+                Err(e) => error!("Failed to write to CSV log {}: {}", self.path.display(), e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{GpuMetrics, SpeedReason};
+
+    fn readout(timestamp: f64) -> GpuReadout {
+        let metrics = GpuMetrics { temp: Some(55.0), fan_speed: Some(40.0), ..Default::default() };
+        GpuReadout { timestamp, id: String::from("sim-0"), label: String::from("Simulated GPU, 70% load"), metrics, temp: 55, speed_output: 40, changed: true, reason: SpeedReason::Curve, failsafe: None, emergency: Vec::new() }
+    }
+
+    /// Empty directory for one test, removed again by the test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-gpu-fan-control-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn format_utc_converts_to_civil_dates() {
+        assert_eq!(format_utc(0.0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_782_400.0), "2000-02-29T00:00:00Z");
+        assert_eq!(format_utc(1_714_570_620.9), "2024-05-01T13:37:00Z");
+        assert_eq!(format_utc(4_107_542_399.0), "2100-02-28T23:59:59Z");
+        assert_eq!(format_utc(-1.0), "1969-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn csv_field_quotes_only_when_needed() {
+        assert_eq!(csv_field("curve"), "curve");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest_files() {
+        let dir = test_dir("csv-size");
+        let path = dir.join("fans.csv");
+        // Any row is past a single byte, so every tick rotates
+        let mut logger = CsvLogger::create(path.to_str().unwrap(), CsvLogPolicy { max_size: 0.000_001, daily: false, keep: 2 }).unwrap();
+        for tick in 0..4 {
+            logger.log(&[readout(1_714_570_620.0 + tick as f64)]);
+        }
+
+        let current = fs::read_to_string(&path).unwrap();
+        assert_eq!(current.lines().count(), 2);
+        assert!(current.starts_with(CSV_HEADER));
+        assert!(current.contains("1714570623.000"));
+        assert!(fs::read_to_string(dir.join("fans.csv.1")).unwrap().contains("1714570622.000"));
+        assert!(fs::read_to_string(dir.join("fans.csv.2")).unwrap().contains("1714570621.000"));
+        assert!(!dir.join("fans.csv.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_daily_on_the_utc_day_change() {
+        let dir = test_dir("csv-daily");
+        let path = dir.join("fans.csv");
+        let mut logger = CsvLogger::create(path.to_str().unwrap(), CsvLogPolicy { max_size: 0.0, daily: true, keep: 5 }).unwrap();
+        logger.day = unix_day(1_714_607_999.0);
+        logger.log(&[readout(1_714_607_999.0)]);
+        assert!(!dir.join("fans.csv.1").exists());
+        logger.log(&[readout(1_714_608_000.0)]);
+
+        let old = fs::read_to_string(dir.join("fans.csv.1")).unwrap();
+        assert!(old.contains("2024-05-01T23:59:59Z"));
+        assert!(fs::read_to_string(&path).unwrap().contains("2024-05-02T00:00:00Z"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+use std::fs::{self, Permissions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Map, Value};
+
+use crate::backend::GpuReadout;
+use crate::config::{string_key, Config};
+use crate::error::Error;
+use crate::logging::{error, info};
+use crate::profile::Profile;
+use crate::trace::readout_to_json;
+
+/// Control socket used when neither `--socket` nor the config file name one.
+pub const DEFAULT_SOCKET_PATH: &str = "/run/rust-gpu-fan-control.sock";
+
+/// Where the control socket lives and who may use it.
+///
+/// The socket is created with mode 0660, so besides root only members of `group`
+/// can query or steer the daemon.
+#[derive(Clone, Debug)]
+pub struct DaemonSettings {
+    pub socket: String,
+    pub group: Option<String>,
+}
+
+impl Default for DaemonSettings {
+    fn default() -> DaemonSettings {
+        DaemonSettings { socket: String::from(DEFAULT_SOCKET_PATH), group: None }
+    }
+}
+
+impl DaemonSettings {
+    /// Takes the socket path and group from the `daemon` object of the config file.
+    ///
+    /// ```json
+    /// "daemon": { "socket": "/run/rust-gpu-fan-control.sock", "group": "wheel" }
+    /// ```
+    pub fn from_json(value: &Value) -> Result<DaemonSettings, String> {
+        let mut settings = DaemonSettings::default();
+
+        if let Some(socket) = string_key(value, "daemon", "socket")? {
+            settings.socket = socket;
+        }
+        settings.group = string_key(value, "daemon", "group")?;
+
+        Ok(settings)
+    }
+}
+
+/// A fixed fan speed replacing the curve, until it expires or is cleared.
+#[derive(Clone, Copy, Debug)]
+pub struct SpeedOverride {
+    pub speed: u8,
+    pub until: Option<Instant>,
+}
+
+/// What the control loop and the control socket share.
+///
+/// The socket only changes these values, the control loop picks them up at the start
+/// of its next tick and stores its readouts back for `status` requests.
+pub struct ControlState {
+    /// Fan curve in use
+    pub profile: Profile,
+    /// Profiles that can be switched to
+    pub profiles: Vec<Profile>,
+    /// Fixed speed replacing the fan curve
+    pub speed_override: Option<SpeedOverride>,
+    /// Whether fan control is handed back to the driver
+    pub paused: bool,
+    /// Config loaded by a `reload` request that the control loop has not picked up yet
+    pub reloaded: Option<Config>,
+    /// Readouts of the latest tick
+    pub readouts: Vec<GpuReadout>,
+}
+
+impl ControlState {
+    pub fn new(profile: Profile, profiles: Vec<Profile>) -> ControlState {
+        ControlState { profile, profiles, speed_override: None, paused: false, reloaded: None, readouts: Vec::new() }
+    }
+
+    /// Switches to another of the available profiles.
+    pub fn set_profile(&mut self, name: &str) -> Result<(), String> {
+        let profile = self.profiles.iter().find(|profile| profile.name == name).cloned().ok_or_else(|| format!("Unknown profile \"{}\"", name))?;
+        info!("Switching to the \"{}\" profile", profile.name);
+        self.profile = profile;
+        Ok(())
+    }
+
+    /// The override in effect, forgetting it once it expired.
+    pub fn active_override(&mut self) -> Option<u8> {
+        if self.speed_override.is_some_and(|speed_override| speed_override.until.is_some_and(|until| Instant::now() >= until)) {
+            self.speed_override = None;
+            info!("Override expired, back to the \"{}\" profile", self.profile.name);
+        }
+        self.speed_override.map(|speed_override| speed_override.speed)
+    }
+}
+
+pub type SharedControlState = Arc<Mutex<ControlState>>;
+
+/// Looks up the id of a group in `/etc/group`.
+fn group_id(name: &str) -> Result<u32, Error> {
+    let groups = fs::read_to_string("/etc/group").map_err(|e| Error::io("Failed to read /etc/group", e))?;
+    groups
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<_>>())
+        .find(|fields| fields.first() == Some(&name))
+        .and_then(|fields| fields.get(2)?.parse().ok())
+        .ok_or_else(|| Error::Config(format!("Unknown group \"{}\" for the control socket", name)))
+}
+
+/// Creates the control socket, replacing one left behind by an instance that is gone.
+fn bind_socket(settings: &DaemonSettings) -> Result<UnixListener, Error> {
+    let path = Path::new(&settings.socket);
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::Config(format!("Another instance is already listening on {}", settings.socket)));
+        }
+        fs::remove_file(path).map_err(|e| Error::io(format!("Failed to remove the stale control socket {}", settings.socket), e))?;
+    }
+
+    let listener = UnixListener::bind(path).map_err(|e| Error::io(format!("Failed to create the control socket {}", settings.socket), e))?;
+    fs::set_permissions(path, Permissions::from_mode(0o660)).map_err(|e| Error::io(format!("Failed to set the permissions of {}", settings.socket), e))?;
+    if let Some(group) = &settings.group {
+        chown(path, None, Some(group_id(group)?)).map_err(|e| Error::io(format!("Failed to hand {} to group {}", settings.socket, group), e))?;
+    }
+    Ok(listener)
+}
+
+/// Starts answering requests on the control socket in the background.
+///
+/// `config_path` is what `reload` reads, the same file the program was started with.
+///
+/// # Errors
+/// Fails if the socket can't be created, or another instance is still listening on it.
+pub fn start_control_socket(settings: &DaemonSettings, state: SharedControlState, config_path: Option<String>) -> Result<(), Error> {
+    let listener = bind_socket(settings)?;
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let state = Arc::clone(&state);
+                    let config_path = config_path.clone();
+                    thread::spawn(move || serve_client(stream, &state, config_path.as_deref()));
+                }
+                Err(e) => error!("Failed to accept a control connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Answers every request line of one connection with a response line.
+fn serve_client(stream: UnixStream, state: &SharedControlState, config_path: Option<&str>) {
+    // Don't let a client that never sends anything hold on to a thread forever
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(60)));
+    let Ok(mut writer) = stream.try_clone() else { return };
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(request) => handle_request(&request, state, config_path),
+            Err(e) => Err(format!("Invalid request: {}", e)),
+        };
+        let response = match response {
+            Ok(Value::Object(mut fields)) => {
+                fields.insert(String::from("ok"), Value::Bool(true));
+                Value::Object(fields)
+            }
+            Ok(_) => json!({ "ok": true }),
+            Err(e) => json!({ "ok": false, "error": e }),
+        };
+
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+/// Status of the daemon as returned by the `status` request.
+fn status_json(state: &mut ControlState) -> Value {
+    let speed_override = state.active_override().map(|speed| {
+        let remaining = state.speed_override.and_then(|speed_override| speed_override.until).map(|until| until.saturating_duration_since(Instant::now()).as_secs_f64().round());
+        json!({ "speed": speed, "remaining": remaining })
+    });
+    json!({
+        "profile": state.profile.name,
+        "paused": state.paused,
+        "override": speed_override,
+        "gpus": state.readouts.iter().map(readout_to_json).collect::<Vec<_>>(),
+    })
+}
+
+/// Runs one request of the control protocol.
+///
+/// ```json
+/// { "command": "status" }
+/// { "command": "profiles" }
+/// { "command": "set_profile", "name": "quiet" }
+/// { "command": "override", "speed": 80, "duration": 600 }
+/// { "command": "clear_override" }
+/// { "command": "pause" }
+/// { "command": "resume" }
+/// { "command": "reload" }
+/// ```
+fn handle_request(request: &Value, state: &SharedControlState, config_path: Option<&str>) -> Result<Value, String> {
+    let command = request.get("command").and_then(Value::as_str).ok_or("\"command\" must be a string")?;
+    let empty = || Value::Object(Map::new());
+
+    // Read the config before taking the lock, the control loop shouldn't wait for the disk
+    let reloaded = if command == "reload" { Some(Config::load(config_path).map_err(|e| e.to_string())?) } else { None };
+
+    let mut state = state.lock().unwrap_or_else(PoisonError::into_inner);
+    match command {
+        "status" => Ok(status_json(&mut state)),
+        "profiles" => Ok(json!({ "profile": state.profile.name, "profiles": state.profiles.iter().map(|profile| profile.name.as_str()).collect::<Vec<_>>() })),
+        "set_profile" => {
+            state.set_profile(request.get("name").and_then(Value::as_str).ok_or("\"name\" must be a string")?)?;
+            Ok(empty())
+        }
+        "override" => {
+            let speed = request.get("speed").and_then(Value::as_u64).filter(|speed| *speed <= 100).ok_or("\"speed\" must be a number from 0 to 100")? as u8;
+            let duration = match request.get("duration") {
+                None | Some(Value::Null) => None,
+                Some(duration) => Some(duration.as_f64().filter(|duration| *duration > 0.0).ok_or("\"duration\" must be a positive number of seconds")?),
+            };
+            match duration {
+                Some(duration) => info!("Overriding the fan curve with {}% for {:.0}s", speed, duration),
+                None => info!("Overriding the fan curve with {}%", speed),
+            }
+            state.speed_override = Some(SpeedOverride { speed, until: duration.map(|duration| Instant::now() + Duration::from_secs_f64(duration)) });
+            Ok(empty())
+        }
+        "clear_override" => {
+            if state.speed_override.take().is_some() {
+                info!("Override cleared, back to the \"{}\" profile", state.profile.name);
+            }
+            Ok(empty())
+        }
+        "pause" => {
+            state.paused = true;
+            Ok(empty())
+        }
+        // Resuming hands the fans back to the curve, whether they were paused or overridden
+        "resume" => {
+            state.paused = false;
+            state.speed_override = None;
+            Ok(empty())
+        }
+        "reload" => {
+            let config = reloaded.unwrap_or_default();
+            // Keep the current profile if the new config still has it
+            let profile = config.find_profile(&state.profile.name).or_else(|| config.find_profile(&config.profile)).cloned().unwrap_or_default();
+            info!("Reloaded the config, using the \"{}\" profile", profile.name);
+            state.profile = profile;
+            state.profiles = config.profiles.clone();
+            state.reloaded = Some(config);
+            Ok(json!({ "profile": state.profile.name }))
+        }
+        _ => Err(format!("Unknown command \"{}\"", command)),
+    }
+}
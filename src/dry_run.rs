@@ -0,0 +1,33 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::logging::info;
+
+/// Set once at startup by `--dry-run`, read by everything that touches the hardware.
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Turns every hardware write and command into a log line for the rest of the run.
+pub fn enable_dry_run() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+/// Whether writes are only logged instead of performed.
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
+/// Logs a write or command skipped because of `--dry-run`.
+pub fn log_dry_run(action: &str) {
+    info!("Dry run: would {}", action);
+}
+
+/// Writes a sysfs file such as `pwm1`, or only logs the write in dry-run mode.
+pub fn write_sysfs(path: &Path, value: &str) -> io::Result<()> {
+    if is_dry_run() {
+        log_dry_run(&format!("write {} to {}", value, path.display()));
+        return Ok(());
+    }
+    fs::write(path, value)
+}
@@ -0,0 +1,392 @@
+use std::process::Command;
+use std::thread;
+use std::time::Instant;
+
+use serde_json::Value;
+
+use crate::backend::{GpuBackend, GpuMetrics};
+use crate::config::{number_key, string_key};
+use crate::dry_run::{is_dry_run, log_dry_run};
+use crate::logging::{critical, error, notice};
+
+/// What to do once a GPU stayed above a critical temperature for long enough.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EmergencyAction {
+    /// Run the fans at 100% regardless of the fan curve
+    FullSpeed,
+    /// Run a shell command, and another one once the GPU cooled down again
+    Command { command: String, clear_command: Option<String> },
+    /// Lower the power limit of the GPU, the previous limit is restored once it cooled down
+    PowerLimit { watts: u32 },
+    /// Run a shutdown command, this is never undone
+    Shutdown { command: String },
+}
+
+impl EmergencyAction {
+    fn name(&self) -> &'static str {
+        match self {
+            EmergencyAction::FullSpeed => "full_speed",
+            EmergencyAction::Command { .. } => "command",
+            EmergencyAction::PowerLimit { .. } => "power_limit",
+            EmergencyAction::Shutdown { .. } => "shutdown",
+        }
+    }
+}
+
+/// Which temperature sensor a rule watches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmergencySensor {
+    Edge,
+    Junction,
+    Memory,
+}
+
+impl EmergencySensor {
+    fn read(self, metrics: &GpuMetrics) -> Option<f32> {
+        match self {
+            EmergencySensor::Edge => metrics.temp,
+            EmergencySensor::Junction => metrics.junction_temp,
+            EmergencySensor::Memory => metrics.memory_temp,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            EmergencySensor::Edge => "temp",
+            EmergencySensor::Junction => "junction",
+            EmergencySensor::Memory => "memory",
+        }
+    }
+}
+
+/// A critical threshold and the action it triggers.
+///
+/// The action triggers once `sensor` stayed at or above `temp` for `sustained` seconds
+/// and is undone once it drops to `clear_temp` or below, the gap between the two keeps
+/// the action from flapping around the threshold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmergencyRule {
+    pub action: EmergencyAction,
+    pub sensor: EmergencySensor,
+    pub temp: f32,
+    pub clear_temp: f32,
+    pub sustained: f32,
+}
+
+impl EmergencyRule {
+    /// Parses one entry of the `emergency` list of the config file.
+    ///
+    /// ```json
+    /// { "action": "power_limit", "sensor": "junction", "temp": 100, "clear_temp": 92, "sustained": 10, "watts": 150 }
+    /// ```
+    fn from_json(index: usize, value: &Value) -> Result<EmergencyRule, String> {
+        let section = format!("emergency[{}]", index);
+        let field = |key: &str| format!("\"{}.{}\"", section, key);
+        let float = |key: &str| number_key(value, &section, key);
+        let string = |key: &str| string_key(value, &section, key);
+
+        let action = match value.get("action").and_then(Value::as_str) {
+            Some("full_speed") => EmergencyAction::FullSpeed,
+            Some("command") => EmergencyAction::Command { command: string("command")?.ok_or(format!("{} is required", field("command")))?, clear_command: string("clear_command")? },
+            Some("power_limit") => EmergencyAction::PowerLimit { watts: value.get("watts").and_then(Value::as_u64).filter(|watts| *watts > 0).ok_or(format!("{} must be a positive number", field("watts")))? as u32 },
+            Some("shutdown") => EmergencyAction::Shutdown { command: string("command")?.unwrap_or_else(|| String::from("systemctl poweroff")) },
+            _ => return Err(format!("{} must be one of full_speed, command, power_limit, shutdown", field("action"))),
+        };
+
+        let sensor = match string("sensor")?.as_deref() {
+            None | Some("temp") => EmergencySensor::Edge,
+            Some("junction") => EmergencySensor::Junction,
+            Some("memory") => EmergencySensor::Memory,
+            Some(_) => return Err(format!("{} must be one of temp, junction, memory", field("sensor"))),
+        };
+
+        let temp = float("temp")?.ok_or(format!("{} is required", field("temp")))?;
+        let clear_temp = float("clear_temp")?.unwrap_or(temp - 5.0);
+        if clear_temp >= temp {
+            return Err(format!("{} must be below {}", field("clear_temp"), field("temp")));
+        }
+
+        Ok(EmergencyRule { action, sensor, temp, clear_temp, sustained: float("sustained")?.unwrap_or(5.0) })
+    }
+}
+
+/// Turns the `emergency` list of the config file into rules, failing on the first invalid entry.
+///
+/// ```json
+/// "emergency": [
+///     { "action": "full_speed", "temp": 90, "sustained": 5 },
+///     { "action": "command", "temp": 92, "command": "pkill -STOP blender", "clear_command": "pkill -CONT blender" },
+///     { "action": "shutdown", "temp": 100, "sustained": 30 }
+/// ]
+/// ```
+pub fn emergency_rules_from_json(value: &Value) -> Result<Vec<EmergencyRule>, String> {
+    value.as_array().ok_or("\"emergency\" must be a list")?.iter().enumerate().map(|(index, rule)| EmergencyRule::from_json(index, rule)).collect()
+}
+
+/// Progress of every rule for one GPU.
+#[derive(Clone, Debug, Default)]
+pub struct EmergencyState {
+    /// Per rule: since when the sensor has been at or above the threshold
+    above_since: Vec<Option<Instant>>,
+    /// Per rule: whether the action is currently in effect
+    active: Vec<bool>,
+}
+
+impl EmergencyState {
+    /// Names of the actions currently in effect.
+    pub fn active_actions(&self, rules: &[EmergencyRule]) -> Vec<String> {
+        rules.iter().zip(&self.active).filter(|(_, active)| **active).map(|(rule, _)| rule.action.name().to_string()).collect()
+    }
+
+    /// Whether any active action asks for the fans to run at 100%.
+    pub fn full_speed(&self, rules: &[EmergencyRule]) -> bool {
+        rules.iter().zip(&self.active).any(|(rule, active)| *active && matches!(rule.action, EmergencyAction::FullSpeed))
+    }
+}
+
+/// Undoes every action still in effect and forgets the progress of all rules.
+///
+/// Used when the rules are replaced, the state only makes sense for the rules it was built from.
+/// A shutdown that already ran is not undone, just like when the GPU cools down.
+pub fn reset_emergency_state(rules: &[EmergencyRule], state: &mut EmergencyState, backend: &mut dyn GpuBackend) {
+    for (rule, _) in rules.iter().zip(&state.active).filter(|(_, active)| **active) {
+        let result = match &rule.action {
+            EmergencyAction::Command { clear_command: Some(clear_command), .. } => {
+                run_command(clear_command, &backend.id(), rule.clear_temp);
+                Ok(())
+            }
+            EmergencyAction::PowerLimit { .. } => backend.set_power_limit(None),
+            EmergencyAction::FullSpeed | EmergencyAction::Command { clear_command: None, .. } | EmergencyAction::Shutdown { .. } => Ok(()),
+        };
+        match result {
+            Ok(()) => notice!(gpu = backend.id(); "Emergency rules changed, undid {} for {}", rule.action.name(), backend.label()),
+            Err(e) => error!(gpu = backend.id(); "Emergency rules changed, failed to undo {} for {}: {}", rule.action.name(), backend.label(), e),
+        }
+    }
+    *state = EmergencyState::default();
+}
+
+/// Describes the readings that led to an action so the log shows why it happened.
+fn describe_metrics(metrics: &GpuMetrics) -> String {
+    let value = |value: Option<f32>, unit: &str| value.map_or(String::from("unknown"), |value| format!("{:.1}{}", value, unit));
+    format!(
+        "temp {}, junction {}, memory {}, fans {}",
+        value(metrics.temp, "°C"),
+        value(metrics.junction_temp, "°C"),
+        value(metrics.memory_temp, "°C"),
+        value(metrics.fan_speed, "%")
+    )
+}
+
+/// Runs a user command through `sh -c` without blocking the control loop.
+///
+/// The GPU and the triggering temperature are passed in `GPU_ID` and `GPU_TEMP`.
+fn run_command(command: &str, gpu_id: &str, temp: f32) {
+    if is_dry_run() {
+        log_dry_run(&format!("run \"{}\" with GPU_ID={} GPU_TEMP={:.1}", command, gpu_id, temp));
+        return;
+    }
+
+    match Command::new("sh").arg("-c").arg(command).env("GPU_ID", gpu_id).env("GPU_TEMP", format!("{:.1}", temp)).spawn() {
+        // Reap the child in the background so it doesn't linger as a zombie
+        Ok(mut child) => {
+            thread::spawn(move || child.wait());
+        }
+        Err(e) => error!("Failed to run \"{}\": {}", command, e),
+    }
+}
+
+/// Checks every rule against the latest readings and triggers or undoes their actions.
+///
+/// Readings without the watched sensor leave a rule as it is, the failsafe already
+/// deals with GPUs that can't be read.
+pub fn evaluate_emergency_rules(rules: &[EmergencyRule], state: &mut EmergencyState, backend: &mut dyn GpuBackend, metrics: Option<&GpuMetrics>) {
+    state.above_since.resize(rules.len(), None);
+    state.active.resize(rules.len(), false);
+    let Some(metrics) = metrics else { return };
+    let label = backend.label();
+
+    for (index, rule) in rules.iter().enumerate() {
+        let Some(temp) = rule.sensor.read(metrics) else { continue };
+
+        if !state.active[index] {
+            if temp < rule.temp {
+                state.above_since[index] = None;
+                continue;
+            }
+
+            let above_since = *state.above_since[index].get_or_insert_with(Instant::now);
+            if above_since.elapsed().as_secs_f32() < rule.sustained {
+                continue;
+            }
+
+            state.active[index] = true;
+            let result = match &rule.action {
+                EmergencyAction::FullSpeed => Ok(String::from("forcing fans to 100%")),
+                EmergencyAction::Command { command, .. } => {
+                    run_command(command, &backend.id(), temp);
+                    Ok(format!("running \"{}\"", command))
+                }
+                EmergencyAction::PowerLimit { watts } => backend.set_power_limit(Some(*watts)).map(|_| format!("lowering the power limit to {}W", watts)),
+                EmergencyAction::Shutdown { command } => {
+                    run_command(command, &backend.id(), temp);
+                    Ok(format!("shutting down with \"{}\"", command))
+                }
+            };
+
+            let trigger = format!("{} at {:.1}°C for {:.0}s (limit {:.1}°C)", rule.sensor.name(), temp, above_since.elapsed().as_secs_f32(), rule.temp);
+            match result {
+                Ok(what) => critical!(gpu = backend.id(), temp = temp; "EMERGENCY {}: {}, {} [{}]", label, trigger, what, describe_metrics(metrics)),
+                Err(e) => critical!(gpu = backend.id(), temp = temp; "EMERGENCY {}: {}, failed to run {}: {} [{}]", label, trigger, rule.action.name(), e, describe_metrics(metrics)),
+            }
+        } else if temp <= rule.clear_temp {
+            state.active[index] = false;
+            state.above_since[index] = None;
+
+            let result = match &rule.action {
+                EmergencyAction::FullSpeed => Ok(String::from("handing the fans back to the curve")),
+                EmergencyAction::Command { clear_command: Some(clear_command), .. } => {
+                    run_command(clear_command, &backend.id(), temp);
+                    Ok(format!("running \"{}\"", clear_command))
+                }
+                EmergencyAction::Command { clear_command: None, .. } => Ok(String::from("nothing to undo")),
+                EmergencyAction::PowerLimit { .. } => backend.set_power_limit(None).map(|_| String::from("restoring the power limit")),
+                // There is no coming back from a shutdown, keep it active so it only runs once
+                EmergencyAction::Shutdown { .. } => {
+                    state.active[index] = true;
+                    continue;
+                }
+            };
+
+            let trigger = format!("{} back down to {:.1}°C (clears at {:.1}°C)", rule.sensor.name(), temp, rule.clear_temp);
+            match result {
+                Ok(what) => notice!(gpu = backend.id(), temp = temp; "Emergency over for {}: {}, {} [{}]", label, trigger, what, describe_metrics(metrics)),
+                Err(e) => error!(gpu = backend.id(), temp = temp; "Emergency over for {}: {}, failed to undo {}: {} [{}]", label, trigger, rule.action.name(), e, describe_metrics(metrics)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Restorer;
+    use crate::error::Error;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Backend that only remembers the power limit it was given.
+    struct FakeGpu {
+        power_limit: Option<u32>,
+    }
+
+    impl GpuBackend for FakeGpu {
+        fn id(&self) -> String {
+            String::from("fake-0")
+        }
+
+        fn label(&self) -> String {
+            String::from("Fake GPU")
+        }
+
+        fn read_metrics(&mut self) -> Result<GpuMetrics, Error> {
+            Ok(GpuMetrics::default())
+        }
+
+        fn set_fan_speed(&mut self, _speed: u8) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn set_power_limit(&mut self, watts: Option<u32>) -> Result<(), Error> {
+            self.power_limit = watts;
+            Ok(())
+        }
+
+        fn restorer(&self) -> Restorer {
+            Arc::new(|| {})
+        }
+    }
+
+    fn rule(action: EmergencyAction, sustained: f32) -> EmergencyRule {
+        EmergencyRule { action, sensor: EmergencySensor::Edge, temp: 90.0, clear_temp: 85.0, sustained }
+    }
+
+    fn at(temp: f32) -> GpuMetrics {
+        GpuMetrics { temp: Some(temp), ..GpuMetrics::default() }
+    }
+
+    #[test]
+    fn actions_wait_for_the_sustained_duration() {
+        let rules = vec![rule(EmergencyAction::FullSpeed, 0.05)];
+        let mut state = EmergencyState::default();
+        let mut gpu = FakeGpu { power_limit: None };
+
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(95.0)));
+        assert!(!state.full_speed(&rules));
+
+        // Dipping below the threshold starts the wait over
+        thread::sleep(Duration::from_millis(60));
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(89.0)));
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(95.0)));
+        assert!(!state.full_speed(&rules));
+
+        thread::sleep(Duration::from_millis(60));
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(95.0)));
+        assert!(state.full_speed(&rules));
+    }
+
+    #[test]
+    fn actions_are_undone_at_the_clear_temperature() {
+        let rules = vec![rule(EmergencyAction::PowerLimit { watts: 150 }, 0.0)];
+        let mut state = EmergencyState::default();
+        let mut gpu = FakeGpu { power_limit: None };
+
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(90.0)));
+        assert_eq!(gpu.power_limit, Some(150));
+
+        // Between the two thresholds nothing changes
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(87.0)));
+        assert_eq!(gpu.power_limit, Some(150));
+        assert_eq!(state.active_actions(&rules), vec!["power_limit"]);
+
+        // A reading without the watched sensor leaves the rule alone as well
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&GpuMetrics::default()));
+        assert_eq!(gpu.power_limit, Some(150));
+
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(85.0)));
+        assert_eq!(gpu.power_limit, None);
+        assert!(state.active_actions(&rules).is_empty());
+    }
+
+    #[test]
+    fn shutdown_is_never_undone() {
+        // `true` stands in for the real shutdown command
+        let rules = vec![rule(EmergencyAction::Shutdown { command: String::from("true") }, 0.0)];
+        let mut state = EmergencyState::default();
+        let mut gpu = FakeGpu { power_limit: None };
+
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(95.0)));
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(40.0)));
+        assert_eq!(state.active_actions(&rules), vec!["shutdown"]);
+    }
+
+    #[test]
+    fn reset_undoes_active_actions() {
+        let rules = vec![rule(EmergencyAction::PowerLimit { watts: 150 }, 0.0), rule(EmergencyAction::FullSpeed, 0.0)];
+        let mut state = EmergencyState::default();
+        let mut gpu = FakeGpu { power_limit: None };
+
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(95.0)));
+        assert_eq!(gpu.power_limit, Some(150));
+        assert!(state.full_speed(&rules));
+
+        reset_emergency_state(&rules, &mut state, &mut gpu);
+        assert_eq!(gpu.power_limit, None);
+        assert!(state.active_actions(&rules).is_empty());
+
+        // The new rules start from scratch instead of inheriting the old flags
+        let rules = vec![rule(EmergencyAction::FullSpeed, 60.0)];
+        evaluate_emergency_rules(&rules, &mut state, &mut gpu, Some(&at(95.0)));
+        assert!(!state.full_speed(&rules));
+    }
+}
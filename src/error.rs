@@ -0,0 +1,76 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong in the program.
+///
+/// Errors are either fatal, meaning fan control can't work at all and the program
+/// should stop, or recoverable, meaning the control loop carries on (using the failsafe
+/// speed if need be). Transient errors are recoverable ones worth retrying right away.
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid command line arguments or config file
+    Config(String),
+    /// Missing privileges, e.g. not running as root
+    Permission(String),
+    /// No supported GPU was found, or the tools its backend relies on are missing
+    Unsupported(String),
+    /// Another program is already controlling the fans
+    Conflict(String),
+    /// The GPU could not be read or written this time, e.g. `nvidia-smi` printed garbage
+    Transient(String),
+    /// Reading or writing a file failed
+    Io { context: String, source: io::Error },
+    /// Checking for or downloading an update failed
+    Update(String),
+}
+
+impl Error {
+    /// Wraps an I/O error together with what was being done when it happened.
+    pub fn io(context: impl Into<String>, source: io::Error) -> Error {
+        Error::Io { context: context.into(), source }
+    }
+
+    /// Whether retrying the failed operation right away might succeed.
+    ///
+    /// A tool that timed out is not retried, it would most likely hang again and hold up the failsafe.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::Transient(_) => true,
+            Error::Io { source, .. } => matches!(source.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy),
+            _ => false,
+        }
+    }
+
+    /// Whether the program can't do its job anymore and has to stop.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            Error::Config(_) | Error::Permission(_) | Error::Unsupported(_) | Error::Conflict(_) => true,
+            Error::Io { source, .. } => source.kind() == io::ErrorKind::PermissionDenied,
+            Error::Transient(_) | Error::Update(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(message) | Error::Permission(message) | Error::Unsupported(message) | Error::Conflict(message) | Error::Transient(message) | Error::Update(message) => write!(f, "{}", message),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Error {
+        Error::Update(e.to_string())
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::backend::GpuReadout;
+use crate::error::Error;
+
+/// What the exporter serves, updated by the control loop after every tick.
+#[derive(Default)]
+struct ExporterState {
+    profile: String,
+    readouts: Vec<GpuReadout>,
+    /// Per GPU id: how often a new speed was written to the fans
+    speed_changes: BTreeMap<String, u64>,
+    /// Per GPU id: how often the failsafe took over
+    failsafe_entries: BTreeMap<String, u64>,
+}
+
+/// Serves the latest readings in the Prometheus text exposition format.
+pub struct MetricsExporter {
+    state: Arc<Mutex<ExporterState>>,
+}
+
+impl MetricsExporter {
+    /// Starts serving `/metrics` on `address`, e.g. `127.0.0.1:9835`.
+    ///
+    /// # Errors
+    /// Fails if the address can't be listened on.
+    pub fn start(address: &str) -> Result<MetricsExporter, Error> {
+        let listener = TcpListener::bind(address).map_err(|e| Error::io(format!("Failed to listen for metrics on {}", address), e))?;
+        let state = Arc::new(Mutex::new(ExporterState::default()));
+
+        let served_state = Arc::clone(&state);
+        thread::spawn(move || {
+            // Every scrape gets its own thread, a client that never finishes its request only holds up itself
+            for stream in listener.incoming().flatten() {
+                let served_state = Arc::clone(&served_state);
+                thread::spawn(move || serve_scrape(stream, &served_state));
+            }
+        });
+
+        Ok(MetricsExporter { state })
+    }
+
+    /// Stores the readouts of the latest tick and counts speed changes and failsafe entries.
+    pub fn update(&self, readouts: &[GpuReadout], profile: &str) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for readout in readouts {
+            let entered_failsafe = readout.failsafe.is_some() && !state.readouts.iter().any(|previous| previous.id == readout.id && previous.failsafe.is_some());
+            *state.failsafe_entries.entry(readout.id.clone()).or_default() += entered_failsafe as u64;
+            *state.speed_changes.entry(readout.id.clone()).or_default() += readout.changed as u64;
+        }
+        state.profile = profile.to_string();
+        state.readouts = readouts.to_vec();
+    }
+}
+
+/// Answers one HTTP request, `/metrics` gets the metrics and anything else a 404.
+fn serve_scrape(mut stream: TcpStream, state: &Mutex<ExporterState>) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let Ok(reader_stream) = stream.try_clone() else { return };
+    let mut reader = BufReader::new(reader_stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    // Skip the headers, nothing in them changes the answer
+    let mut header = String::new();
+    while reader.read_line(&mut header).is_ok_and(|read| read > 0) && header.trim() != "" {
+        header.clear();
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = if path == "/metrics" || path.starts_with("/metrics?") {
+        ("200 OK", render_metrics(&state.lock().unwrap_or_else(PoisonError::into_inner)))
+    } else {
+        ("404 Not Found", String::from("Metrics are served on /metrics\n"))
+    };
+    let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+}
+
+/// Escapes a label value as the exposition format requires.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Renders every metric, GPUs are labelled with their id since labels such as the simulated load change.
+fn render_metrics(state: &ExporterState) -> String {
+    let mut output = String::new();
+    let mut family = |name: &str, kind: &str, help: &str, samples: Vec<(String, f64)>| {
+        let _ = writeln!(output, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for (labels, value) in samples {
+            // Two decimals keep `f32` noise out of the output
+            let _ = writeln!(output, "{}{{{}}} {}", name, labels, (value * 100.0).round() / 100.0);
+        }
+    };
+    let gpu_labels = |readout: &GpuReadout| format!("gpu=\"{}\"", escape_label(&readout.id));
+    let per_gpu = |value: &dyn Fn(&GpuReadout) -> Option<f64>| state.readouts.iter().filter_map(|readout| Some((gpu_labels(readout), value(readout)?))).collect::<Vec<_>>();
+
+    let temperatures = state
+        .readouts
+        .iter()
+        .flat_map(|readout| {
+            let metrics = &readout.metrics;
+            [("edge", metrics.temp), ("junction", metrics.junction_temp), ("memory", metrics.memory_temp)]
+                .into_iter()
+                .filter_map(move |(sensor, value)| Some((format!("{},sensor=\"{}\"", gpu_labels(readout), sensor), value? as f64)))
+        })
+        .collect();
+    family("gpu_fan_control_temperature_celsius", "gauge", "GPU temperature by sensor.", temperatures);
+    family("gpu_fan_control_fan_target_percent", "gauge", "Fan speed the control loop asked for.", per_gpu(&|readout| Some(readout.speed_output as f64)));
+    family("gpu_fan_control_fan_speed_percent", "gauge", "Fan speed reported by the GPU.", per_gpu(&|readout| readout.metrics.fan_speed.map(f64::from)));
+    family("gpu_fan_control_fan_rpm", "gauge", "Fan speed reported by the GPU in RPM.", per_gpu(&|readout| readout.metrics.fan_rpm.map(f64::from)));
+    let reasons = state.readouts.iter().map(|readout| (format!("{},reason=\"{}\"", gpu_labels(readout), readout.reason.as_str()), 1.0)).collect();
+    family("gpu_fan_control_speed_reason_info", "gauge", "What picked the fan speed.", reasons);
+    family("gpu_fan_control_failsafe", "gauge", "Whether the failsafe speed is used instead of the fan curve.", per_gpu(&|readout| Some(readout.failsafe.is_some() as u8 as f64)));
+    family("gpu_fan_control_emergency_actions", "gauge", "Number of emergency actions in effect.", per_gpu(&|readout| Some(readout.emergency.len() as f64)));
+    family("gpu_fan_control_speed_changes_total", "counter", "Number of times a new speed was written to the fans.", per_gpu(&|readout| state.speed_changes.get(&readout.id).map(|count| *count as f64)));
+    family("gpu_fan_control_failsafe_entries_total", "counter", "Number of times the failsafe took over.", per_gpu(&|readout| state.failsafe_entries.get(&readout.id).map(|count| *count as f64)));
+    family("gpu_fan_control_profile_info", "gauge", "Fan curve profile in use.", vec![(format!("profile=\"{}\"", escape_label(&state.profile)), 1.0)]);
+
+    output
+}
@@ -0,0 +1,216 @@
+use serde_json::Value;
+
+use crate::backend::GpuMetrics;
+use crate::config::{bool_key, number_key, percent_key};
+use crate::error::Error;
+
+/// What to do when a GPU can't be trusted to report its temperature.
+///
+/// A reading that is missing, outside the plausible range or took longer than
+/// `stale_after` seconds to arrive sends the fans to `speed` until good readings return.
+/// So do sensors that keep reporting exactly the same values for `frozen_after` seconds,
+/// which is what a hung driver tends to look like. That check is off by default: an idle
+/// NVIDIA card only reports whole degrees and fan percentages, which can legitimately hold
+/// still for a long time.
+///
+/// Once a written speed had `settle_after` seconds to take effect, the fan speed the GPU
+/// reports should be within `speed_tolerance` of it. Fans that don't follow are warned
+/// about, or also trigger the failsafe speed with `failsafe_on_mismatch`. Fans written
+/// `stall_min_speed` or more that report 0 RPM, or less than `stall_ratio` of the written
+/// speed, are considered stalled and trigger the failsafe speed unless `failsafe_on_stall` is off.
+#[derive(Clone, Debug)]
+pub struct FailsafePolicy {
+    /// Fan speed in percent used while the failsafe is active
+    pub speed: u8,
+    /// Readings older than this many seconds are treated as stale
+    pub stale_after: f32,
+    /// Sensors reporting the same values for this many seconds are treated as frozen, 0 turns the check off
+    pub frozen_after: f32,
+    /// Lowest temperature in °C accepted as a real reading
+    pub min_temp: f32,
+    /// Highest temperature in °C accepted as a real reading
+    pub max_temp: f32,
+    /// Seconds the fans get to reach a newly written speed before it is checked
+    pub settle_after: f32,
+    /// Largest difference in percentage points between the written and the reported fan speed
+    pub speed_tolerance: f32,
+    /// Whether fans that don't follow the written speed trigger the failsafe speed
+    pub failsafe_on_mismatch: bool,
+    /// Lowest written speed in percent at which fans are expected to spin
+    pub stall_min_speed: u8,
+    /// Fraction of the written speed below which the fans are considered stalled
+    pub stall_ratio: f32,
+    /// Whether stalled fans trigger the failsafe speed
+    pub failsafe_on_stall: bool,
+}
+
+impl Default for FailsafePolicy {
+    fn default() -> FailsafePolicy {
+        FailsafePolicy { speed: 100, stale_after: 5.0, frozen_after: 0.0, min_temp: 1.0, max_temp: 125.0, settle_after: 5.0, speed_tolerance: 15.0, failsafe_on_mismatch: false, stall_min_speed: 30, stall_ratio: 0.3, failsafe_on_stall: true }
+    }
+}
+
+impl FailsafePolicy {
+    /// Reads the `failsafe` object of the config file on top of the defaults above.
+    ///
+    /// ```json
+    /// "failsafe": { "speed": 100, "stale_after": 5, "frozen_after": 0, "min_temp": 1, "max_temp": 125, "settle_after": 5, "speed_tolerance": 15, "failsafe_on_mismatch": false,
+    ///               "stall_min_speed": 30, "stall_ratio": 0.3, "failsafe_on_stall": true }
+    /// ```
+    pub fn from_json(value: &Value) -> Result<FailsafePolicy, String> {
+        let mut policy = FailsafePolicy::default();
+
+        if let Some(speed) = percent_key(value, "failsafe", "speed")? {
+            policy.speed = speed;
+        }
+        if let Some(stale_after) = number_key(value, "failsafe", "stale_after")? {
+            policy.stale_after = stale_after;
+        }
+        if let Some(frozen_after) = number_key(value, "failsafe", "frozen_after")? {
+            policy.frozen_after = frozen_after;
+        }
+        if let Some(min_temp) = number_key(value, "failsafe", "min_temp")? {
+            policy.min_temp = min_temp;
+        }
+        if let Some(max_temp) = number_key(value, "failsafe", "max_temp")? {
+            policy.max_temp = max_temp;
+        }
+        if let Some(settle_after) = number_key(value, "failsafe", "settle_after")? {
+            policy.settle_after = settle_after;
+        }
+        if let Some(speed_tolerance) = number_key(value, "failsafe", "speed_tolerance")? {
+            policy.speed_tolerance = speed_tolerance;
+        }
+        if let Some(failsafe_on_mismatch) = bool_key(value, "failsafe", "failsafe_on_mismatch")? {
+            policy.failsafe_on_mismatch = failsafe_on_mismatch;
+        }
+        if let Some(stall_min_speed) = percent_key(value, "failsafe", "stall_min_speed")? {
+            policy.stall_min_speed = stall_min_speed;
+        }
+        if let Some(stall_ratio) = number_key(value, "failsafe", "stall_ratio")? {
+            policy.stall_ratio = stall_ratio;
+        }
+        if let Some(failsafe_on_stall) = bool_key(value, "failsafe", "failsafe_on_stall")? {
+            policy.failsafe_on_stall = failsafe_on_stall;
+        }
+
+        Ok(policy)
+    }
+
+    /// Checks a reading and returns the temperature to feed the fan curve.
+    ///
+    /// `age` is how long ago the reading was requested and `unchanged_for` how long the
+    /// sensors have been reporting exactly these values.
+    ///
+    /// # Errors
+    /// Returns why the reading can't be trusted, which is shown to the user.
+    pub fn check_reading(&self, metrics: Result<&GpuMetrics, &Error>, age: f32, unchanged_for: f32) -> Result<f32, String> {
+        let temp = metrics.map_err(|e| format!("no reading from the GPU ({})", e))?.temp.ok_or("temperature sensor reported nothing")?;
+
+        if !(self.min_temp..=self.max_temp).contains(&temp) {
+            return Err(format!("implausible temperature of {:.1}°C", temp));
+        }
+        if age > self.stale_after {
+            return Err(format!("reading is {:.1}s old", age));
+        }
+        if self.frozen_after > 0.0 && unchanged_for > self.frozen_after {
+            return Err(format!("sensors reported the same values for {:.0}s", unchanged_for));
+        }
+
+        Ok(temp)
+    }
+
+    /// Checks that the fans follow the last written speed, see [`FailsafePolicy::settle_after`].
+    ///
+    /// The PWM duty cycle is compared where the GPU reports it (AMD), since RPM doesn't
+    /// scale linearly with it, otherwise the fan speed the GPU reports in percent. Whether
+    /// the fans actually spin is up to [`FailsafePolicy::check_fan_stall`]. Nothing is
+    /// checked if the GPU reports neither.
+    ///
+    /// # Errors
+    /// Returns how far off the fans are, which is shown to the user.
+    pub fn check_fan_speed(&self, metrics: Option<&GpuMetrics>, written_speed: u8) -> Result<(), String> {
+        let Some(metrics) = metrics else { return Ok(()) };
+
+        match (metrics.fan_duty, metrics.fan_speed) {
+            (Some(fan_duty), _) if (fan_duty - written_speed as f32).abs() > self.speed_tolerance => Err(format!("the PWM duty cycle reads back as {:.0}% instead of {}%", fan_duty, written_speed)),
+            (None, Some(fan_speed)) if (fan_speed - written_speed as f32).abs() > self.speed_tolerance => Err(format!("fans run at {:.0}% instead of {}%", fan_speed, written_speed)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that fans written a speed they should spin at actually spin.
+    ///
+    /// The RPM is compared against the card's maximum when both are reported, otherwise
+    /// the fan speed the GPU reports in percent is used.
+    ///
+    /// # Errors
+    /// Returns what the fans report, which is shown to the user.
+    pub fn check_fan_stall(&self, metrics: Option<&GpuMetrics>, written_speed: u8) -> Result<(), String> {
+        if written_speed < self.stall_min_speed {
+            return Ok(());
+        }
+        let Some(metrics) = metrics else { return Ok(()) };
+
+        if metrics.fan_rpm == Some(0.0) {
+            return Err(format!("fans report 0 RPM while set to {}%", written_speed));
+        }
+
+        let reported_speed = match (metrics.fan_rpm, metrics.fan_max_rpm) {
+            (Some(rpm), Some(max_rpm)) if max_rpm > 0.0 => Some(rpm / max_rpm * 100.0),
+            _ => metrics.fan_speed,
+        };
+        match reported_speed {
+            Some(reported_speed) if reported_speed < written_speed as f32 * self.stall_ratio => Err(format!("fans run at {:.0}% while set to {}%", reported_speed, written_speed)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fans(fan_rpm: Option<f32>, fan_max_rpm: Option<f32>, fan_speed: Option<f32>) -> GpuMetrics {
+        GpuMetrics { temp: Some(60.0), fan_rpm, fan_max_rpm, fan_speed, ..GpuMetrics::default() }
+    }
+
+    #[test]
+    fn stall_compares_the_rpm_against_the_maximum() {
+        let policy = FailsafePolicy::default();
+
+        // 600 of 3000 RPM is 20%, below 30% of the 80% written
+        assert!(policy.check_fan_stall(Some(&fans(Some(600.0), Some(3000.0), Some(80.0))), 80).is_err());
+        assert!(policy.check_fan_stall(Some(&fans(Some(900.0), Some(3000.0), None)), 80).is_ok());
+        assert!(policy.check_fan_stall(Some(&fans(Some(0.0), None, Some(80.0))), 80).is_err());
+
+        // Without a maximum RPM the reported percentage is used
+        assert!(policy.check_fan_stall(Some(&fans(Some(600.0), None, Some(20.0))), 80).is_err());
+        assert!(policy.check_fan_stall(Some(&fans(None, None, Some(25.0))), 80).is_ok());
+        assert!(policy.check_fan_stall(Some(&fans(None, None, None)), 80).is_ok());
+    }
+
+    #[test]
+    fn slow_fan_speeds_are_never_a_stall() {
+        let policy = FailsafePolicy::default();
+
+        // Plenty of cards stop their fans below some speed
+        assert!(policy.check_fan_stall(Some(&fans(Some(0.0), Some(3000.0), Some(0.0))), 29).is_ok());
+        assert!(policy.check_fan_stall(Some(&fans(Some(0.0), Some(3000.0), Some(0.0))), 30).is_err());
+        assert!(policy.check_fan_stall(None, 100).is_ok());
+    }
+
+    #[test]
+    fn fan_speed_prefers_the_duty_cycle_and_allows_the_tolerance() {
+        let policy = FailsafePolicy::default();
+
+        assert!(policy.check_fan_speed(Some(&fans(None, None, Some(65.0))), 50).is_ok());
+        assert!(policy.check_fan_speed(Some(&fans(None, None, Some(66.0))), 50).is_err());
+
+        // The duty cycle wins over a percentage that lags behind
+        let duty = GpuMetrics { fan_duty: Some(50.0), ..fans(None, None, Some(20.0)) };
+        assert!(policy.check_fan_speed(Some(&duty), 50).is_ok());
+        let duty = GpuMetrics { fan_duty: Some(20.0), ..fans(None, None, Some(50.0)) };
+        assert!(policy.check_fan_speed(Some(&duty), 50).unwrap_err().contains("PWM duty cycle"));
+    }
+}
@@ -0,0 +1,59 @@
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+
+use crate::dry_run::is_dry_run;
+use crate::error::Error;
+use crate::state::STATE_DIR;
+
+/// Lock files held by this process, the locks go away when they are closed.
+static HELD_LOCKS: Mutex<Vec<File>> = Mutex::new(Vec::new());
+
+/// Path of the lock file of a GPU, see [`crate::backend::GpuBackend::id`].
+fn lock_path(gpu_id: &str) -> PathBuf {
+    PathBuf::from(STATE_DIR).join(format!("{}.pid", gpu_id.replace('/', "_")))
+}
+
+/// Makes sure no other instance controls the GPU for as long as we run.
+///
+/// `/run/rust-gpu-fan-control/<gpu>.pid` is locked with `flock` and kept open until the
+/// fans are handed back. The kernel drops the lock when the process ends however it ends,
+/// so a killed instance never leaves a stale lock behind. The PID in the file only serves
+/// the error message. Dry runs don't write to the fans, so they neither take nor respect the lock.
+///
+/// # Errors
+/// Fails if another instance owns the GPU or the lock file can't be created.
+pub fn acquire_gpu_lock(gpu_id: &str) -> Result<(), Error> {
+    if is_dry_run() {
+        return Ok(());
+    }
+
+    let path = lock_path(gpu_id);
+    fs::create_dir_all(STATE_DIR).map_err(|e| Error::io(format!("Failed to create {}", STATE_DIR), e))?;
+    // Not truncated on open, the PID of the current owner must survive until we own the lock
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path).map_err(|e| Error::io(format!("Failed to open the lock {}", path.display()), e))?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            let pid = pid.trim();
+            return Err(Error::Conflict(format!("{} is already controlled by another instance (PID {}), stop it first", gpu_id, if pid.is_empty() { "unknown" } else { pid })));
+        }
+        Err(TryLockError::Error(e)) => return Err(Error::io(format!("Failed to lock {}", path.display()), e)),
+    }
+
+    file.set_len(0).and_then(|_| file.rewind()).and_then(|_| writeln!(file, "{}", process::id())).map_err(|e| Error::io(format!("Failed to write {}", path.display()), e))?;
+    HELD_LOCKS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(file);
+    Ok(())
+}
+
+/// Gives up every lock this process holds, once the fans were handed back to the driver.
+///
+/// The files stay in place, removing them could delete a lock another instance just took.
+pub fn release_gpu_locks() {
+    HELD_LOCKS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clear();
+}
@@ -0,0 +1,209 @@
+use std::io::{self, IsTerminal, Write};
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+use owo_colors::OwoColorize;
+
+/// Environment variable setting the level when none of `--log-level`, `--verbose` and `-q` is given.
+pub const LOG_ENV: &str = "RUST_GPU_FAN_CONTROL_LOG";
+
+/// Name the messages are tagged with in journald and syslog.
+const SYSLOG_IDENTIFIER: &str = "rust-gpu-fan-control";
+
+/// journald's native protocol socket
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+/// The syslog socket
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Severity of a message, the values are the syslog priorities journald uses as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// The fans can't be trusted to cool the GPU: failsafe, stalls and emergencies
+    Critical = 2,
+    Error = 3,
+    Warn = 4,
+    /// Good news worth noticing, e.g. readings coming back
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl Level {
+    /// Parses a level as given in [`LOG_ENV`], e.g. `debug`.
+    pub fn parse(name: &str) -> Option<Level> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "critical" => Some(Level::Critical),
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warn),
+            "notice" => Some(Level::Notice),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+/// Where messages should go, as given with `--log-target`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// The terminal when stderr is one, journald or syslog otherwise, e.g. under systemd
+    Auto,
+    Terminal,
+    Journald,
+    Syslog,
+}
+
+impl LogTarget {
+    pub fn parse(name: &str) -> Option<LogTarget> {
+        match name {
+            "auto" => Some(LogTarget::Auto),
+            "terminal" => Some(LogTarget::Terminal),
+            "journald" => Some(LogTarget::Journald),
+            "syslog" => Some(LogTarget::Syslog),
+            _ => None,
+        }
+    }
+}
+
+/// Where messages go.
+enum Sink {
+    Terminal,
+    Journald(UnixDatagram),
+    Syslog(UnixDatagram),
+}
+
+/// Most verbose level that is still written, [`Level::Info`] until set up.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Set up once at startup, the terminal is used until then.
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Picks the level and where messages go for the rest of the run.
+///
+/// [`LogTarget::Auto`] writes to the terminal when stderr is one. Otherwise, as in a
+/// systemd unit with or without `--daemon`, messages go to journald if it is running and to
+/// syslog if not. Whenever the chosen sink can't be reached the terminal is used instead.
+pub fn init_logging(level: Level, target: LogTarget) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+
+    let connect = |path: &str| UnixDatagram::unbound().and_then(|socket| socket.connect(path).map(|_| socket)).ok();
+    let sink = match target {
+        LogTarget::Auto if io::stderr().is_terminal() => None,
+        LogTarget::Auto if Path::new(JOURNALD_SOCKET).exists() => connect(JOURNALD_SOCKET).map(Sink::Journald),
+        LogTarget::Auto => connect(SYSLOG_SOCKET).map(Sink::Syslog),
+        LogTarget::Terminal => None,
+        LogTarget::Journald => connect(JOURNALD_SOCKET).map(Sink::Journald),
+        LogTarget::Syslog => connect(SYSLOG_SOCKET).map(Sink::Syslog),
+    };
+    let unreachable = sink.is_none() && matches!(target, LogTarget::Journald | LogTarget::Syslog);
+    let _ = SINK.set(sink.unwrap_or(Sink::Terminal));
+    if unreachable {
+        let name = if target == LogTarget::Journald { "journald" } else { "syslog" };
+        write_log(Level::Warn, &[], &format!("{} can't be reached, logging to the terminal instead", name));
+    }
+}
+
+/// Appends one field in journald's native format, values with newlines are length prefixed.
+fn journald_field(datagram: &mut Vec<u8>, key: &str, value: &str) {
+    datagram.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        datagram.push(b'\n');
+        datagram.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        datagram.push(b'=');
+    }
+    datagram.extend_from_slice(value.as_bytes());
+    datagram.push(b'\n');
+}
+
+/// Writes a message with extra fields such as the GPU id, used by the logging macros.
+///
+/// journald stores the fields as upper case keys (`GPU`, `SPEED`), syslog and the
+/// terminal get them appended as `key=value` outside of interactive runs.
+pub fn write_log(level: Level, fields: &[(&str, String)], message: &str) {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+
+    match SINK.get().unwrap_or(&Sink::Terminal) {
+        Sink::Terminal => {
+            // journald and syslog carry the level as the priority, the terminal only has the text
+            let message = match level {
+                Level::Error => format!("Error: {}", message),
+                Level::Warn => format!("Warning: {}", message),
+                _ => message.to_string(),
+            };
+            let stderr = io::stderr();
+            let line = if stderr.is_terminal() {
+                match level {
+                    Level::Critical | Level::Error => message.red().to_string(),
+                    Level::Warn => message.yellow().to_string(),
+                    Level::Notice => message.green().to_string(),
+                    Level::Info => message.to_string(),
+                    Level::Debug => message.dimmed().to_string(),
+                }
+            } else {
+                fields.iter().fold(message.to_string(), |line, (key, value)| format!("{} {}={}", line, key, value))
+            };
+            let _ = writeln!(stderr.lock(), "{}", line);
+        }
+        Sink::Journald(socket) => {
+            let mut datagram = Vec::new();
+            journald_field(&mut datagram, "MESSAGE", message);
+            journald_field(&mut datagram, "PRIORITY", &(level as u8).to_string());
+            journald_field(&mut datagram, "SYSLOG_IDENTIFIER", SYSLOG_IDENTIFIER);
+            for (key, value) in fields {
+                journald_field(&mut datagram, &key.to_ascii_uppercase(), value);
+            }
+            let _ = socket.send(&datagram);
+        }
+        Sink::Syslog(socket) => {
+            // Facility 3 is "daemon"
+            let fields = fields.iter().map(|(key, value)| format!(" {}={}", key, value)).collect::<String>();
+            let _ = socket.send(format!("<{}>{}[{}]: {}{}", 3 * 8 + level as u8, SYSLOG_IDENTIFIER, process::id(), message, fields).as_bytes());
+        }
+    }
+}
+
+/// Logs at the given level, optionally with `key = value` fields before a `;`:
+///
+/// ```ignore
+/// log_at!(Level::Info, "Resumed after {}s", seconds);
+/// log_at!(Level::Debug, gpu = id, speed = speed; "Set fans to {}%", speed);
+/// ```
+macro_rules! log_at {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::logging::write_log($level, &[$((stringify!($key), $value.to_string())),+], &format!($($arg)+))
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::write_log($level, &[], &format!($($arg)+))
+    };
+}
+
+macro_rules! critical {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Critical, $($arg)+) };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Error, $($arg)+) };
+}
+
+macro_rules! warning {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+macro_rules! notice {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Notice, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::logging::log_at!($crate::logging::Level::Debug, $($arg)+) };
+}
+
+pub(crate) use {critical, debug, error, info, log_at, notice, warning};
@@ -1,19 +1,25 @@
 use std::fs::{metadata, remove_file};
+use std::io::{self, Write};
 use std::path::Path;
 use std::process::{exit, Command};
-use std::sync::mpsc;
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 use std::{env, thread};
 
-use clap::{command, Arg, ArgAction};
+use clap::{command, value_parser, Arg, ArgAction, ArgMatches, Command as ClapCommand};
 use owo_colors::OwoColorize;
+use serde_json::{json, Value};
 use termion::terminal_size;
 
 mod update_func_logic;
 use update_func_logic::*;
 
+mod bar;
+use bar::{format_bar, sample_gpus, BarFormat};
+
 mod calculations;
-use calculations::*;
+use calculations::get_current_nvidia_temp;
 
 mod colour_math;
 use colour_math::{rgb_temp, rgb_temp_f32, RgbColor};
@@ -21,22 +27,88 @@ use colour_math::{rgb_temp, rgb_temp_f32, RgbColor};
 mod checksum_func;
 use checksum_func::compute_file_sha256;
 
+mod backend;
+use backend::{BackendKind, ControlledGpu, GpuMetrics, GpuReadout, SpeedReason};
+
 mod amdgpu;
-use amdgpu::get_amdgpu_fan_metrics;
+use amdgpu::{enumerate_amdgpu_cards, select_amdgpu_cards, AmdGpuSensors};
+
+mod nvidia;
+use nvidia::NvidiaGpu;
+
+mod sim;
+use sim::{parse_load_profile, SimulatedGpu};
+
+mod logging;
+use logging::{error, info, init_logging, notice, warning, Level, LogTarget, LOG_ENV};
+
+mod lock;
+use lock::acquire_gpu_lock;
+
+mod profile;
+use profile::Profile;
+
+mod config;
+use config::Config;
+
+mod conflicts;
+
+mod control;
+use control::{control_gpu, Tick};
+
+mod csv_log;
+use csv_log::CsvLogger;
+
+mod daemon;
+use daemon::{start_control_socket, ControlState};
+
+mod client;
+use client::{check_response, format_response, parse_duration, send_request};
+
+mod dry_run;
+use dry_run::{enable_dry_run, is_dry_run};
+
+mod emergency;
+use emergency::reset_emergency_state;
+
+mod error;
+use error::Error;
+
+mod exporter;
+use exporter::MetricsExporter;
+
+mod failsafe;
+
+mod mqtt;
+use mqtt::{parse_broker, start_mqtt};
+
+mod report;
+use report::compare_profiles;
+
+mod resume;
+use resume::ResumeDetector;
+
+mod retry;
+
+mod signals;
+use signals::{setup_exit_handlers, RestoreGuard};
+
+mod startup;
+
+mod state;
+
+mod trace;
+use trace::{load_trace, readout_to_json, split_trace_by_gpu, unix_timestamp, ReplayGpu, TraceRecorder};
 
 mod compile_flag_helper;
-use compile_flag_helper::{CAPITALIZED_BINARY_NAME, FAN_AMOUNT};
+use compile_flag_helper::CAPITALIZED_BINARY_NAME;
 
 /// Defines the interval for refreshing the screen and recalculating screen boundaries.
-/// This function determines the appropriate refresh rate based on the GPU manufacturer.
-fn define_refresh_time(gpu_manufacturer: u8) -> f32 {
-    if gpu_manufacturer == 0 {
-        0.3 // Refresh rate for NVIDIA GPUs (in seconds)
-    } else if gpu_manufacturer == 1 {
-        0.1 // Refresh rate for AMD GPUs (in seconds)
-    } else {
-        eprintln!("Error: Unknown GPU or no GPU found");
-        exit(1)
+/// This function determines the appropriate refresh rate based on the GPU backend.
+fn define_refresh_time(backend_kind: BackendKind) -> f32 {
+    match backend_kind {
+        BackendKind::Nvidia => 0.3,                                        // Refresh rate for NVIDIA GPUs (in seconds)
+        BackendKind::Amd | BackendKind::Sim | BackendKind::Replay => 0.1, // Refresh rate for AMD, simulated and replayed GPUs (in seconds)
     }
 }
 
@@ -58,36 +130,25 @@ const fn get_version() -> &'static str {
 const VERSION: &str = get_version();
 
 /// Check if user is root
-fn check_sudo() {
-    if let Ok(user) = std::env::var("USER") {
-        if user != "root" {
-            println!("This script must be run with sudo privileges.");
-            exit(1);
-        }
-    } else {
-        println!("Unable to retrieve user information.");
-        exit(1);
+fn check_sudo() -> Result<(), Error> {
+    match std::env::var("USER") {
+        Ok(user) if user == "root" => Ok(()),
+        Ok(_) => Err(Error::Permission(String::from("This script must be run with sudo privileges."))),
+        Err(_) => Err(Error::Permission(String::from("Unable to retrieve user information."))),
     }
 }
 
 /// Used to find if the user has a supported gpu
-fn find_gpu_manufacturer() -> u8 {
-    let output = match Command::new("lspci").arg("-nnk").output() {
-        Ok(output) => output,
-        Err(_) => {
-            eprintln!("Error: Failed to execute lspci command");
-            exit(1);
-        }
-    };
+fn find_gpu_manufacturer() -> Result<BackendKind, Error> {
+    let output = Command::new("lspci").arg("-nnk").output().map_err(|e| Error::io("Failed to execute lspci command", e))?;
 
     let output_str = String::from_utf8_lossy(&output.stdout);
     if output_str.contains("NVIDIA") {
-        0
+        Ok(BackendKind::Nvidia)
     } else if output_str.contains("amdgpu") {
-        1
+        Ok(BackendKind::Amd)
     } else {
-        eprintln!("Error: Unknown GPU or no GPU found");
-        exit(1)
+        Err(Error::Unsupported(String::from("Unknown GPU or no GPU found")))
     }
 }
 
@@ -96,79 +157,114 @@ fn celcius_to_fahrenheit(input_celcius: u8) -> u8 {
     (input_celcius as f32 * 1.8 + 32.0) as u8
 }
 
+/// Parses a factor that only makes sense above zero, such as a speed up of simulated time.
+fn positive_number<T: FromStr + Copy + Into<f64>>(text: &str) -> Result<T, String> {
+    match text.parse::<T>() {
+        Ok(value) if value.into() > 0.0 && value.into().is_finite() => Ok(value),
+        Ok(_) => Err(String::from("must be a number greater than 0")),
+        Err(_) => Err(format!("\"{}\" is not a number", text)),
+    }
+}
+
 /// Sleep calling thread for x seconds
+/// Lets the command line options win over the config file, also after a reload.
+fn apply_cli_overrides(config: &mut Config, args: &ArgMatches) -> Result<(), Error> {
+    if let Some(&timeout) = args.get_one::<f32>("startup-timeout") {
+        config.startup.timeout = timeout;
+    }
+    if let Some(socket) = args.get_one::<String>("socket") {
+        config.daemon.socket = socket.clone();
+    }
+    if let Some(broker) = args.get_one::<String>("mqtt") {
+        let (host, port) = parse_broker(broker).map_err(Error::Config)?;
+        config.mqtt.host = Some(host);
+        if let Some(port) = port {
+            config.mqtt.port = port;
+        }
+    }
+    Ok(())
+}
+
 fn sleep(input_sec: f32) {
     thread::sleep(Duration::from_secs_f32(input_sec));
 }
 
 /// Retrieves the path of the current executable binary at runtime.
-fn get_binary_path() -> String {
-    let binary_path = if let Ok(path) = env::current_exe() {
-        if let Some(file_name) = path.file_name() {
-            if let Some(name) = file_name.to_str() {
-                name.to_string()
-            } else {
-                println!("Can't find binary_path exiting make a issue on github");
-                exit(0);
-            }
-        } else {
-            println!("Can't find binary_path (path.file_name) exiting make a issue on github");
-            exit(0);
-        }
+fn get_binary_path() -> Result<String, Error> {
+    let path = env::current_exe().map_err(|e| Error::io("Can't find binary_path (env::current_exe()) make a issue on github", e))?;
+    let file_name = path.file_name().ok_or_else(|| Error::Update(String::from("Can't find binary_path (path.file_name) make a issue on github")))?;
+    let name = file_name.to_str().ok_or_else(|| Error::Update(String::from("Can't find binary_path make a issue on github")))?;
+    Ok(name.to_string())
+}
+
+fn get_current_exe_dir() -> Result<String, Error> {
+    let path = env::current_exe().map_err(|e| Error::io("Error get_current_exe_dir", e))?;
+    let parent_dir = path.parent().ok_or_else(|| Error::Update(String::from("Failed to obtain parent directory of the current executable.")))?;
+    Ok(parent_dir.to_string_lossy().into_owned())
+}
+
+/// Prints everything known about one GPU, one centered line per value.
+///
+/// The label is only printed when more than one GPU is shown, optional sensors are
+/// skipped when the backend does not report them.
+fn print_gpu_readout(width: usize, readout: &GpuReadout, show_label: bool, rgb_array: &RgbColor, fahrenheit: bool) {
+    let metrics = &readout.metrics;
+
+    if show_label {
+        println!("{: >width$}", readout.label, width = (width.saturating_sub(readout.label.len()) / 2) + readout.label.len());
+    }
+
+    // Make it obvious that the fan curve is not in charge right now
+    if let Some(reason) = &readout.failsafe {
+        let failsafe_str = format!("FAILSAFE: {}, fans at {}%", reason, readout.speed_output);
+        println!("{: >width$}", failsafe_str.red(), width = (width.saturating_sub(failsafe_str.len()) / 2) + failsafe_str.len());
+    }
+
+    if !readout.emergency.is_empty() {
+        let emergency_str = format!("EMERGENCY: {}", readout.emergency.join(", "));
+        println!("{: >width$}", emergency_str.red(), width = (width.saturating_sub(emergency_str.len()) / 2) + emergency_str.len());
+    }
+
+    let rgb_value_temp = rgb_temp(rgb_array, readout.temp);
+    let gpu_temp_str: String = if metrics.temp.is_none() {
+        String::from("Gpu temp: unknown")
+    } else if fahrenheit {
+        format!("Gpu temp: {}°F", celcius_to_fahrenheit(readout.temp))
     } else {
-        println!("Can't find binary_path (env::current_exe()) exiting make a issue on github");
-        exit(0);
+        format!("Gpu temp: {}°C", readout.temp)
     };
-    binary_path
-}
+    println!("{: >width$}", gpu_temp_str.truecolor(rgb_value_temp.0, rgb_value_temp.1, rgb_value_temp.2), width = (width.saturating_sub(gpu_temp_str.len()) / 2) + gpu_temp_str.len());
 
-fn setup_ctrl_c_handler(gpu_manufacturer: u8) {
-    // Create a channel for communication between threads
-    let (tx, rx) = mpsc::channel();
-
-    // Set up the Ctrl+C handler
-    ctrlc::set_handler(move || {
-        // Send a signal on the channel when Ctrl+C is pressed
-        tx.send(()).expect("Could not send signal on channel.");
-    })
-    .expect("Error setting Ctrl+C handler");
-
-    // Spawn a new thread to execute the cleanup function
-    thread::spawn(move || {
-        // Wait for the signal from the main thread
-        rx.recv().expect("Could not receive from channel.");
-
-        // Added if statement here for later AMD GPU integration
-        if gpu_manufacturer == 0 {
-            // Execute the cleanup function for NVIDIA
-            cleanup_nvidia();
-        } else if gpu_manufacturer == 1 {
-            print!("\x1B[?25h");
-        } else {
-            eprintln!("Error: Unknown GPU or no GPU found");
-            exit(1);
-        }
+    // Calculate junction pos
+    print_centered_colored_string(width, "Junction/hotspot: {}°C", metrics.junction_temp.map(|value| value as u8 as f32), Some(50.0), Some(95.0), rgb_array, fahrenheit, Some("Junction/hotspot: {}°F"));
 
-        // Exit the program
-        exit(0);
-    });
-}
+    // Calculate Vram/Memory pos
+    print_centered_colored_string(width, "Memory/vram temp: {}°C", metrics.memory_temp.map(|value| value as u8 as f32), Some(60.0), Some(90.0), rgb_array, fahrenheit, Some("Memory/vram temp: {}°F"));
 
-fn get_current_exe_dir() -> String {
-    let current_exe_dir = match env::current_exe() {
-        Ok(path) => {
-            if let Some(parent_dir) = path.parent() {
-                parent_dir.to_string_lossy().into_owned()
-            } else {
-                panic!("Failed to obtain parent directory of the current executable.");
-            }
-        }
-        Err(e) => {
-            eprintln!("Error get_current_exe_dir: {}", e);
-            exit(1);
-        }
+    // Calculate rpm pos
+    print_centered_colored_string(width, "Current fan RPM: {}", metrics.fan_rpm.map(f32::round), metrics.fan_min_rpm, metrics.fan_max_rpm, rgb_array, false, Some(""));
+
+    // Calculate fanspeed pos, prefer what the card reports over what we asked for
+    if let Some(fan_speed_percentage) = metrics.fan_speed {
+        print_centered_colored_string(width, "Current fan speed: {}%", Some(fan_speed_percentage as u8 as f32), Some(30.0), Some(85.0), rgb_array, false, Some(""));
+    } else {
+        let rgb_value_speed_output = rgb_temp(rgb_array, readout.speed_output);
+        let fan_speed_output_str = format!("Current fan speed: {}%", readout.speed_output);
+        println!(
+            "{: >width$}",
+            fan_speed_output_str.truecolor(rgb_value_speed_output.0, rgb_value_speed_output.1, rgb_value_speed_output.2),
+            width = (width.saturating_sub(fan_speed_output_str.len()) / 2) + fan_speed_output_str.len()
+        );
+    }
+
+    let status = if readout.reason == SpeedReason::Monitor {
+        String::from("Monitoring only, the driver controls the fans")
+    } else if readout.changed {
+        format!("Changed Speed to {}", readout.speed_output)
+    } else {
+        format!("Skipped execution as speed has not changed from {}", readout.speed_output)
     };
-    current_exe_dir
+    println!("{: >width$}", status, width = (width.saturating_sub(status.len()) / 2) + status.len());
 }
 
 /// Prints a colored string centered in the terminal
@@ -182,6 +278,7 @@ fn get_current_exe_dir() -> String {
 /// * `rgb_array` - The RgbColor struct for color calculations
 /// * `use_alt_str` - Boolean flag to use the alternate string
 /// * `alt_format_str` - The alternate format string (used if use_alt_str is true)
+#[allow(clippy::too_many_arguments)]
 fn print_centered_colored_string(
     width: usize,
     format_str: &str,
@@ -199,18 +296,26 @@ fn print_centered_colored_string(
         let chosen_format_str = if use_alt_str { alt_format_str.unwrap_or(format_str) } else { format_str };
         // Format the string with the provided value
         let formatted_value = if use_alt_str { celcius_to_fahrenheit(value as u8) as f32 } else { value };
-        let formatted_str = format!("{}", chosen_format_str.replace("{}", &formatted_value.to_string()));
+        let formatted_str = chosen_format_str.replace("{}", &formatted_value.to_string());
         // Calculate the center position for the string
-        let center = (width - formatted_str.len()) / 2;
+        let center = width.saturating_sub(formatted_str.len()) / 2;
         // Print the formatted string with calculated color and centering
         println!("{: >width$}", formatted_str.truecolor(color.0, color.1, color.2), width = center + formatted_str.len());
     }
 }
 
 fn main() {
-    // Make sure the executing user is sudo
-    check_sudo();
+    if let Err(e) = run() {
+        error!("{}", e);
+        exit(1);
+    }
+}
 
+/// Parses the arguments and runs whatever they ask for, most of the time the control loop.
+///
+/// # Errors
+/// Only fatal errors end up here, recoverable ones are dealt with inside the control loop.
+fn run() -> Result<(), Error> {
     // Set flags/arguments
     let mut args = command!()
         .disable_version_flag(true)
@@ -218,11 +323,120 @@ fn main() {
         .arg(Arg::new("update-now").short('u').long("update").help("Update to the latest version if available").action(ArgAction::SetTrue))
         .arg(Arg::new("no-tui").short('n').long("no_tui_output").help("Run without text user interface (for background operation)").action(ArgAction::SetTrue))
         .arg(Arg::new("version-num").short('v').long("version").help("Show current version").action(ArgAction::SetTrue))
-        .arg(Arg::new("test-true").short('t').long("test_fan").help("Test GPU fan control by setting to 100% (NVIDIA only)").action(ArgAction::SetTrue))
+        .arg(Arg::new("test-true").short('t').long("test_fan").help("Test GPU fan control by setting to 100%").action(ArgAction::SetTrue))
         .arg(Arg::new("fahrenheit-id").short('f').long("fahrenheit").help("Display temperatures in Fahrenheit").action(ArgAction::SetTrue));
 
+    args = args.arg(Arg::new("verbose").long("verbose").global(true).action(ArgAction::Count).help("Log more, e.g. every fan speed written (overrides RUST_GPU_FAN_CONTROL_LOG)"));
+    args = args.arg(Arg::new("quiet").short('q').long("quiet").global(true).action(ArgAction::Count).help("Log less, -q only warnings and errors, -qq only errors (overrides RUST_GPU_FAN_CONTROL_LOG)"));
+    args = args.arg(Arg::new("log-level").long("log-level").value_name("LEVEL").global(true).value_parser(["critical", "error", "warning", "notice", "info", "debug"]).help("Log this level and above, wins over --verbose and -q"));
+    args = args.arg(Arg::new("log-target").long("log-target").value_name("TARGET").global(true).value_parser(["auto", "terminal", "journald", "syslog"]).default_value("auto").help("Where to log, \"auto\" uses the terminal if stderr is one and journald or syslog otherwise"));
+
     args = args.arg(Arg::new("force-nvidia").long("nvidia").help("Force NVIDIA GPU detection").action(ArgAction::SetTrue));
     args = args.arg(Arg::new("force-amd").long("amd").help("Force AMD GPU detection").action(ArgAction::SetTrue));
+    args = args.arg(
+        Arg::new("backend")
+            .long("backend")
+            .value_name("BACKEND")
+            .value_parser(["nvidia", "amd", "sim"])
+            .help("Select the GPU backend instead of detecting it, \"sim\" runs a simulated GPU that needs no hardware or root"),
+    );
+    args = args.arg(
+        Arg::new("amd-card")
+            .long("amd-card")
+            .value_name("CARD")
+            .default_value("0")
+            .help("Select the AMD card by index, PCI slot (e.g. 03:00.0) or card name (e.g. card1), or \"all\" to control every card"),
+    );
+    args = args.arg(Arg::new("amd-fan-control").long("amd-fan-control").help("Drive the fans of AMD cards with the fan curve through pwm1, without it AMD cards are only monitored").action(ArgAction::SetTrue));
+    args = args.arg(Arg::new("list-amd-cards").long("list-amd-cards").help("List the detected AMD cards and exit").action(ArgAction::SetTrue));
+
+    // Simulated GPU settings (only used with --backend sim)
+    args = args.arg(
+        Arg::new("sim-load")
+            .long("sim-load")
+            .value_name("PROFILE")
+            .default_value("gaming")
+            .help("Load profile of the simulated GPU: idle, gaming, render, burst or load%:seconds pairs such as 100:30,10:60"),
+    );
+    args = args.arg(Arg::new("sim-ambient").long("sim-ambient").value_name("°C").value_parser(value_parser!(f32)).default_value("25").help("Ambient temperature of the simulated GPU"));
+    args = args.arg(Arg::new("sim-cooling").long("sim-cooling").value_name("FACTOR").value_parser(positive_number::<f32>).default_value("1.0").help("Fan cooling effectiveness of the simulated GPU (1.0 is a typical card)"));
+    args = args.arg(Arg::new("sim-speed").long("sim-speed").value_name("FACTOR").value_parser(positive_number::<f32>).default_value("1.0").help("Run the simulation this many times faster than real time"));
+
+    // Config file and fan curve selection
+    args = args.arg(Arg::new("config").long("config").value_name("FILE").global(true).help("Read settings and profiles from this JSON config file instead of /etc/rust-gpu-fan-control.json"));
+    args = args.arg(Arg::new("profile").long("profile").value_name("NAME").help("Fan curve profile to use (default, quiet, performance or one from the config file)"));
+
+    args = args.arg(
+        Arg::new("startup-timeout")
+            .long("startup-timeout")
+            .value_name("SECONDS")
+            .value_parser(value_parser!(f32))
+            .help("Keep retrying to find the GPU driver for this long at startup, e.g. when started at boot"),
+    );
+    args = args.arg(Arg::new("dry-run").long("dry-run").help("Run the full control loop but only log the fan writes and commands instead of executing them, needs no root").action(ArgAction::SetTrue));
+
+    // Daemon mode with a control socket
+    args = args.arg(Arg::new("daemon").long("daemon").help("Run headless and accept status queries and commands on a Unix control socket").action(ArgAction::SetTrue));
+    args = args.arg(Arg::new("socket").long("socket").value_name("PATH").global(true).help("Path of the control socket, defaults to /run/rust-gpu-fan-control.sock"));
+
+    args = args.arg(
+        Arg::new("output")
+            .long("output")
+            .value_name("FORMAT")
+            .value_parser(["tui", "jsonl"])
+            .default_value("tui")
+            .help("How to show every tick: the TUI, or \"jsonl\" for one JSON object per GPU per tick on stdout"),
+    );
+    args = args.arg(Arg::new("log-csv").long("log-csv").value_name("FILE").help("Append every tick's metrics and speed decision to a CSV file, rotated as set in the config file"));
+    args = args.arg(Arg::new("mqtt").long("mqtt").value_name("HOST[:PORT]").help("Publish readings to an MQTT broker with Home Assistant discovery, other settings come from the config file"));
+    args = args.arg(Arg::new("metrics-listen").long("metrics-listen").value_name("ADDRESS").help("Serve Prometheus metrics on this address, e.g. 127.0.0.1:9835"));
+
+    // Trace recording and replay
+    args = args.arg(Arg::new("record").long("record").value_name("FILE").help("Append every tick's metrics and speed decision to a JSON Lines trace file"));
+    args = args.arg(Arg::new("replay").long("replay").value_name("FILE").help("Feed a recorded trace through the current fan curve instead of reading a GPU"));
+    args = args.arg(Arg::new("replay-speed").long("replay-speed").value_name("FACTOR").value_parser(positive_number::<f64>).default_value("1.0").help("Play the trace back this many times faster than it was recorded"));
+
+    args = args.subcommand(
+        ClapCommand::new("compare")
+            .about("Run profiles over a recorded trace and compare how each fan curve would have behaved")
+            .arg(Arg::new("trace").value_name("TRACE").required(true).help("Trace recorded with --record"))
+            .arg(Arg::new("profile").short('p').long("profile").value_name("NAME").action(ArgAction::Append).help("Profile to compare, repeat for every profile (defaults to all profiles)"))
+            .arg(
+                Arg::new("threshold")
+                    .long("threshold")
+                    .value_name("°C")
+                    .value_parser(value_parser!(u8))
+                    .action(ArgAction::Append)
+                    .default_values(["70", "80", "90"])
+                    .help("Report how often the temperature went above this value, repeat for more thresholds"),
+            ),
+    );
+
+    // Client commands for a daemon that is already running
+    let json_arg = Arg::new("json").long("json").global(true).help("Print the daemon's JSON response instead of a summary").action(ArgAction::SetTrue);
+    args = args.subcommand(ClapCommand::new("status").about("Show the profile, control state and readings of the running daemon").arg(json_arg.clone()));
+    args = args.subcommand(
+        ClapCommand::new("profile")
+            .about("List the profiles of the running daemon or switch to another one")
+            .arg(json_arg.clone())
+            .subcommand(ClapCommand::new("list").about("List the profiles, the active one is marked with *"))
+            .subcommand(ClapCommand::new("set").about("Switch to another profile").arg(Arg::new("name").value_name("NAME").required(true).help("Profile to switch to"))),
+    );
+    args = args.subcommand(
+        ClapCommand::new("override")
+            .about("Run the fans at a fixed speed instead of the fan curve")
+            .arg(json_arg.clone())
+            .arg(Arg::new("speed").value_name("PERCENT").required(true).value_parser(value_parser!(u8).range(0..=100)).help("Fan speed in percent"))
+            .arg(Arg::new("for").long("for").value_name("DURATION").help("Go back to the fan curve after this long, e.g. 90s, 10m or 1h")),
+    );
+    args = args.subcommand(ClapCommand::new("pause").about("Hand the fans back to the driver until resumed").arg(json_arg.clone()));
+    args = args.subcommand(ClapCommand::new("resume").about("End a pause or override and go back to the fan curve").arg(json_arg.clone()));
+    args = args.subcommand(ClapCommand::new("reload").about("Make the running daemon read its config file again").arg(json_arg));
+    args = args.subcommand(
+        ClapCommand::new("bar")
+            .about("Print a compact temperature and fan speed line for a status bar, from the running daemon or read directly")
+            .arg(Arg::new("format").long("format").value_name("FORMAT").value_parser(["text", "waybar", "polybar", "i3blocks"]).default_value("text").help("Plain text, Waybar JSON, polybar colour tags or i3blocks lines")),
+    );
 
     #[cfg(debug_assertions)]
     {
@@ -231,19 +445,120 @@ fn main() {
 
     let args = args.get_matches();
 
-    // Auto detects gpu to target unless overridden with --amd or --nvidia
-    let gpu_manufacturer = if args.get_flag("force-amd") {
-        1
-    } else if args.get_flag("force-nvidia") {
-        0
-    } else {
-        find_gpu_manufacturer()
+    // --log-level wins over --verbose and -q, which win over the environment, which wins over the default
+    let log_level = match (args.get_count("verbose"), args.get_count("quiet")) {
+        _ if args.contains_id("log-level") => args.get_one::<String>("log-level").and_then(|level| Level::parse(level)).unwrap_or(Level::Info),
+        (0, 0) => env::var(LOG_ENV).ok().and_then(|level| Level::parse(&level)).unwrap_or(Level::Info),
+        (verbose, quiet) if verbose > quiet => Level::Debug,
+        (verbose, quiet) if verbose == quiet => Level::Info,
+        (verbose, quiet) if quiet - verbose == 1 => Level::Warn,
+        _ => Level::Error,
     };
+    init_logging(log_level, args.get_one::<String>("log-target").and_then(|target| LogTarget::parse(target)).unwrap_or(LogTarget::Auto));
+
+    let mut config = Config::load(args.get_one::<String>("config").map(String::as_str))?;
+    apply_cli_overrides(&mut config, &args)?;
+
+    // Offline comparison of fan curves, needs neither a GPU nor root
+    if let Some(("compare", compare_args)) = args.subcommand() {
+        let profiles: Vec<&Profile> = match compare_args.get_many::<String>("profile") {
+            Some(names) => names
+                .map(|name| config.find_profile(name).ok_or_else(|| Error::Config(format!("Unknown profile \"{}\", available profiles: {}", name, config.profile_names()))))
+                .collect::<Result<_, _>>()?,
+            None => config.profiles.iter().collect(),
+        };
+        let thresholds: Vec<u8> = compare_args.get_many::<u8>("threshold").map(|values| values.copied().collect()).unwrap_or_default();
+
+        let readouts = load_trace(compare_args.get_one::<String>("trace").map(String::as_str).unwrap_or_default())?;
+        print!("{}", compare_profiles(readouts, &profiles, &thresholds));
+        return Ok(());
+    }
 
-    // Defines what second interval amd or nvidia ui it refreshed at
-    let refresh_time = define_refresh_time(gpu_manufacturer);
+    // One status bar line, the daemon knows the profile and control state but reading the GPU works without it
+    if let Some(("bar", bar_args)) = args.subcommand() {
+        let format = bar_args.get_one::<String>("format").and_then(|format| BarFormat::parse(format)).unwrap_or(BarFormat::Text);
+        let output = match send_request(&config.daemon.socket, &json!({ "command": "status" })) {
+            Ok(status) => format_bar(status.get("gpus").and_then(Value::as_array).map(Vec::as_slice).unwrap_or_default(), Some(&status), format),
+            Err(_) => {
+                let backend_kind = match args.get_one::<String>("backend").and_then(|name| BackendKind::parse(name)) {
+                    Some(backend_kind) => backend_kind,
+                    None if args.get_flag("force-amd") => BackendKind::Amd,
+                    None if args.get_flag("force-nvidia") => BackendKind::Nvidia,
+                    None => find_gpu_manufacturer()?,
+                };
+                format_bar(&sample_gpus(backend_kind, args.get_one::<String>("amd-card").map(String::as_str).unwrap_or("0"))?, None, format)
+            }
+        };
+        print!("{}", output);
+        return Ok(());
+    }
+
+    // Steer the running daemon instead of starting a second controller
+    if let Some((command, client_args)) = args.subcommand() {
+        let request = match (command, client_args.subcommand()) {
+            ("profile", Some(("set", set_args))) => json!({ "command": "set_profile", "name": set_args.get_one::<String>("name") }),
+            ("profile", _) => json!({ "command": "profiles" }),
+            ("override", _) => {
+                let duration = client_args.get_one::<String>("for").map(|duration| parse_duration(duration)).transpose().map_err(Error::Config)?;
+                json!({ "command": "override", "speed": client_args.get_one::<u8>("speed"), "duration": duration })
+            }
+            (command, _) => json!({ "command": command }),
+        };
+
+        let response = send_request(&config.daemon.socket, &request)?;
+        if client_args.get_flag("json") {
+            println!("{}", response);
+        }
+        check_response(&response)?;
+        if !client_args.get_flag("json") {
+            print!("{}", format_response(&request, &response));
+        }
+        return Ok(());
+    }
+
+    // Fan curve used by the control loop
+    let profile_name = args.get_one::<String>("profile").unwrap_or(&config.profile);
+    let profile = config.find_profile(profile_name).cloned().ok_or_else(|| Error::Config(format!("Unknown profile \"{}\", available profiles: {}", profile_name, config.profile_names())))?;
+
+    // Auto detects gpu to target unless overridden with --replay, --backend, --amd or --nvidia
+    let backend_kind = match args.get_one::<String>("backend").and_then(|name| BackendKind::parse(name)) {
+        _ if args.contains_id("replay") => BackendKind::Replay,
+        Some(backend_kind) => backend_kind,
+        None => {
+            if args.get_flag("force-amd") {
+                BackendKind::Amd
+            } else if args.get_flag("force-nvidia") {
+                BackendKind::Nvidia
+            } else {
+                config.startup.wait_for("a supported GPU", find_gpu_manufacturer)?
+            }
+        }
+    };
 
-    setup_ctrl_c_handler(gpu_manufacturer);
+    if args.get_flag("dry-run") {
+        enable_dry_run();
+    }
+
+    // Make sure the executing user is sudo, dry runs, simulated and replayed GPUs touch no hardware
+    if backend_kind.is_hardware() && !is_dry_run() {
+        check_sudo()?;
+    }
+
+    // Defines what second interval amd or nvidia ui it refreshed at
+    let refresh_time = define_refresh_time(backend_kind);
+
+    let daemon = args.get_flag("daemon");
+    let jsonl_output = args.get_one::<String>("output").is_some_and(|output| output == "jsonl");
+    // The daemon has no terminal to draw the TUI on, and JSON Lines would be mangled by it
+    let no_tui = args.get_flag("no-tui") || daemon || jsonl_output;
+    // Without the TUI stdout may be a JSON Lines stream, so messages go through the logger instead
+    let report = |message: &str| {
+        if no_tui {
+            notice!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    };
 
     {
         // Performs a standard version check at startup and notifies if an update is available
@@ -254,10 +569,11 @@ fn main() {
                 Err(_) => (false, String::from("0.0.0")), // Default values in the event of an error
             };
             if is_older {
-                let binary_path = get_binary_path();
-                println!("Current Version: \"{VERSION}\" is behind repo: \"{}\"", repo_version);
-                println!("Please update to the new version using \"sudo ./{} -u\"", binary_path);
-                println!("Resuming normal operation in 10 seconds");
+                // Only used in the hint, so not knowing it is no reason to stop
+                let binary_path = get_binary_path().unwrap_or_else(|_| String::from(CAPITALIZED_BINARY_NAME));
+                report(&format!("Current Version: \"{VERSION}\" is behind repo: \"{}\"", repo_version));
+                report(&format!("Please update to the new version using \"sudo ./{} -u\"", binary_path));
+                report("Resuming normal operation in 10 seconds");
                 sleep(10.0);
             }
         }
@@ -271,12 +587,12 @@ fn main() {
                 Err(_) => String::from("0.0.0"), // Default to initial version if unable to retrieve current version
             };
 
-            let binary_path = get_binary_path();
-            let current_exe_dir = get_current_exe_dir();
+            let binary_path = get_binary_path()?;
+            let current_exe_dir = get_current_exe_dir()?;
             let current_exe_dir_path = &format!("{}/{}", current_exe_dir, binary_path);
             let file_path_tmp = &format!("{}-dl_tmp", current_exe_dir_path);
 
-            let checksums_vec = update_func(CAPITALIZED_BINARY_NAME, Path::new(file_path_tmp));
+            let checksums_vec = update_func(CAPITALIZED_BINARY_NAME, Path::new(file_path_tmp))?;
 
             let mut repo_bin_sha256_result: String = "Error".to_string();
             for checksum in &checksums_vec {
@@ -287,205 +603,252 @@ fn main() {
             }
 
             // Calculate the SHA-256 checksum of the updated binary
-            let updated_bin_sha256 = compute_file_sha256(file_path_tmp);
+            let updated_bin_sha256 = compute_file_sha256(file_path_tmp)?;
 
             if repo_bin_sha256_result == updated_bin_sha256 {
                 update_func_commit(Path::new(current_exe_dir_path), Path::new(file_path_tmp));
-                println!("Checksums Match the repositorys.");
+                report("Checksums Match the repositorys.");
             } else {
-                println!("{}", "Issue during download process checksums do not match the repositorys.".red());
+                if no_tui {
+                    error!("Issue during download process checksums do not match the repositorys.");
+                } else {
+                    println!("{}", "Issue during download process checksums do not match the repositorys.".red());
+                }
                 if metadata(file_path_tmp).is_ok() {
                     if let Err(err) = remove_file(file_path_tmp) {
-                        eprintln!("Error: {}", err);
+                        error!("{}", err);
                     } else {
-                        println!("Tmp_file '{}' successfully deleted", file_path_tmp);
+                        report(&format!("Tmp_file '{}' successfully deleted", file_path_tmp));
                     }
                 } else {
-                    println!("File '{}' does not exist", file_path_tmp);
+                    report(&format!("File '{}' does not exist", file_path_tmp));
                 }
             }
 
-            println!("Downloaded version: {}", repo_version);
-            exit(0);
+            report(&format!("Downloaded version: {}", repo_version));
+            return Ok(());
+        }
+
+        // List every detected AMD card along with the values accepted by --amd-card
+        if args.get_flag("list-amd-cards") {
+            let cards = enumerate_amdgpu_cards();
+            if cards.is_empty() {
+                println!("No AMD cards found");
+            }
+            for card in cards {
+                println!("{}: {} {}", card.index, card.card_name, card.pci_address);
+            }
+            return Ok(());
         }
 
         // Display the current version number of the compiled binary
         if args.get_flag("version-num") {
             println!("Version: {}", VERSION);
-            exit(0);
+            return Ok(());
         }
 
         #[cfg(debug_assertions)]
         if args.get_flag("simulate-temp-range") {
             println!("Simulating all temperature ranges:");
             for temp in 10..=100 {
-                let speed = diff_func(temp);
+                let speed = profile.speed_for(temp);
                 println!("Input Temperature: {}°C, Output Fan Speed: {}%", temp, speed);
             }
-            exit(0);
+            return Ok(());
         }
+    }
 
-        // Test GPU responsiveness by setting fan speed to 100%
-        if args.get_flag("test-true") {
-            println!("Test starting");
+    // Two controllers writing to the same fans make them jump back and forth
+    let amd_fan_control = args.get_flag("amd-fan-control");
+    if backend_kind == BackendKind::Nvidia || (backend_kind == BackendKind::Amd && amd_fan_control) {
+        config.conflicts.check_running_controllers(is_dry_run())?;
+    }
 
-            // Added if statement here for later amd gpu intergration
-            if gpu_manufacturer == 0 {
-                for faninc in 0..FAN_AMOUNT {
-                    Command::new("nvidia-settings").arg("-a").arg(&format!("GPUTargetFanSpeed[fan:{}]=100", faninc)).output().expect("nvidia-settings command failed to execute");
+    // Build the list of GPUs the control loop drives, whatever was taken over is handed back on any exit
+    let gpus: Arc<Mutex<Vec<ControlledGpu>>> = Arc::new(Mutex::new(Vec::new()));
+    let _restore_guard = RestoreGuard(Arc::clone(&gpus));
+    let mut gpu_list = gpus.lock().unwrap_or_else(PoisonError::into_inner);
+    match backend_kind {
+        BackendKind::Nvidia => {
+            // At boot nvidia-smi fails until the driver is loaded
+            config.startup.wait_for("the NVIDIA driver", get_current_nvidia_temp)?;
+            // Taken before the backend snapshots the fans, that snapshot belongs to whoever owns the GPU
+            acquire_gpu_lock(&format!("nvidia-{}", GPU_NUMBER))?;
+            gpu_list.push(ControlledGpu::new(Box::new(NvidiaGpu::new())));
+        }
+        BackendKind::Amd => {
+            // Sensor handles are discovered once per selected card and reused every tick
+            let selector = args.get_one::<String>("amd-card").map(String::as_str).unwrap_or("0");
+            let cards = config.startup.wait_for("the amdgpu hwmon", || select_amdgpu_cards(enumerate_amdgpu_cards(), selector))?;
+            for card in cards {
+                // Monitoring leaves the fans alone, so it doesn't stand in the way of whoever controls them
+                if amd_fan_control {
+                    acquire_gpu_lock(&format!("amdgpu-{}", card.pci_address))?;
                 }
-            } else {
-                eprintln!("Error: Unknown GPU or no GPU found");
-                exit(1);
-            }
-            // Pause execution and instruct the user to terminate the program using Ctrl+C
-            println!("Press Ctrl+C to exit");
-            loop {
-                sleep(1.0);
+                gpu_list.push(ControlledGpu::new(Box::new(AmdGpuSensors::new(card, amd_fan_control))));
             }
         }
+        BackendKind::Sim => {
+            let load_profile = parse_load_profile(args.get_one::<String>("sim-load").map(String::as_str).unwrap_or("gaming")).map_err(Error::Config)?;
+            let ambient = *args.get_one::<f32>("sim-ambient").unwrap_or(&25.0);
+            let cooling = *args.get_one::<f32>("sim-cooling").unwrap_or(&1.0);
+            let time_scale = *args.get_one::<f32>("sim-speed").unwrap_or(&1.0);
+            gpu_list.push(ControlledGpu::new(Box::new(SimulatedGpu::new(load_profile, ambient, cooling, time_scale))));
+        }
+        BackendKind::Replay => {
+            let trace_path = args.get_one::<String>("replay").map(String::as_str).unwrap_or_default();
+            let readouts = load_trace(trace_path)?;
+            let time_scale = *args.get_one::<f64>("replay-speed").unwrap_or(&1.0);
+            gpu_list.extend(split_trace_by_gpu(readouts).into_iter().map(|samples| ControlledGpu::new(Box::new(ReplayGpu::new(samples, time_scale)))));
+        }
     }
+    drop(gpu_list);
+
+    let mut recorder = args.get_one::<String>("record").map(|path| TraceRecorder::create(path)).transpose()?;
+    let mut csv_logger = args.get_one::<String>("log-csv").map(|path| CsvLogger::create(path, config.csv_log.clone())).transpose()?;
+    let exporter = args.get_one::<String>("metrics-listen").map(|address| MetricsExporter::start(address)).transpose()?;
 
-    // Define a variable to hold a starting speed value and to hold the current used/selected speed
-    let mut temp_capture_call: u8 = 8;
+    setup_exit_handlers(Arc::clone(&gpus))?;
+
+    // Profile, override and pause state, steered through the control socket in daemon mode
+    let control = Arc::new(Mutex::new(ControlState::new(profile.clone(), config.profiles.clone())));
+    if daemon {
+        start_control_socket(&config.daemon, Arc::clone(&control), args.get_one::<String>("config").cloned())?;
+    }
+    start_mqtt(config.mqtt.clone(), Arc::clone(&control));
+
+    // Test GPU responsiveness by setting fan speed to 100%
+    if args.get_flag("test-true") {
+        println!("Test starting");
+
+        for gpu in gpus.lock().unwrap_or_else(PoisonError::into_inner).iter_mut().filter(|gpu| gpu.backend.controls_fans()) {
+            if let Err(e) = gpu.backend.set_fan_speed(100) {
+                error!("{}", e);
+            }
+        }
+
+        // Pause execution and instruct the user to terminate the program using Ctrl+C
+        println!("Press Ctrl+C to exit");
+        loop {
+            sleep(1.0);
+        }
+    }
 
     // Define width and height variables
     let mut width: usize = 0;
     let mut height: usize = 0;
 
     // Define variables to save current line calculations
-    let mut temp_center: usize = 0;
-    let mut speed_output_center: usize = 0;
-    let mut skip_center: usize = 0;
-    let mut skip_changed_center: usize = 0;
     let mut vertical_center: usize = 0;
 
-    // Define amd specific variables
-    let mut amd_current_rpm: Option<f32> = None;
-    let mut amd_fan_speed_percentage: Option<u8> = None;
-    let mut amd_junction_temp: Option<f32> = None;
-    let mut amd_memory_temp: Option<f32> = None;
-    let mut amd_min_temp: Option<f32> = None;
-    let mut amd_max_temp: Option<f32> = None;
+    let mut sleep_skip: bool = false;
+
+    let mut readouts: Vec<GpuReadout> = Vec::new();
 
-    let mut amd_sleep_skip: bool = false;
+    let mut resume_detector = ResumeDetector::new();
+
+    let mut was_paused = false;
 
     let rgb_array: RgbColor = RgbColor::new();
     loop {
-        let temp: u8;
-
-        // Get info depending on gpu
-        if gpu_manufacturer == 0 {
-            temp = get_current_nvidia_temp();
-        } else if gpu_manufacturer == 1 {
-            let amdgpu_fan_metrics = get_amdgpu_fan_metrics().unwrap();
-
-            amd_current_rpm = amdgpu_fan_metrics.get("Current RPM").map_or_else(
-                || {
-                    if !amd_sleep_skip && !args.get_flag("force-amd") {
-                        println!("Error getting Current RPM info for amd. This could be due to a missing sensor for your GPU model.");
-                    }
-                    None
-                },
-                |&value| Some(value),
-            );
-
-            amd_fan_speed_percentage = amdgpu_fan_metrics.get("Fan Speed Percentage").map_or_else(
-                || {
-                    if !amd_sleep_skip && !args.get_flag("force-amd") {
-                        println!("Error getting Fan Speed Percentage info for amd. This could be due to a missing sensor for your GPU model.");
-                    }
-                    None
-                },
-                |&value| Some(value as u8),
-            );
-
-            temp = amdgpu_fan_metrics
-                .get("Edge Temp")
-                .map_or_else(
-                    || {
-                        if !amd_sleep_skip && !args.get_flag("force-amd") {
-                            println!("Error getting temp info for amd. This could be due to a missing sensor for your GPU model.");
-                        }
-                        None
-                    },
-                    |&value| Some(value as u8),
-                )
-                .unwrap_or(0);
-
-            amd_junction_temp = amdgpu_fan_metrics.get("Junction Temp").map_or_else(
-                || {
-                    if !amd_sleep_skip && !args.get_flag("force-amd") {
-                        println!("Error getting Junction Temp info for amd. This could be due to a missing sensor for your GPU model.");
-                    }
-                    None
-                },
-                |&value| Some(value),
-            );
-
-            amd_memory_temp = amdgpu_fan_metrics.get("Memory Temp").map_or_else(
-                || {
-                    if !amd_sleep_skip && !args.get_flag("force-amd") {
-                        println!("Error getting Memory Temp info for amd. This could be due to a missing sensor for your GPU model.");
-                    }
-                    None
-                },
-                |&value| Some(value),
-            );
-
-            amd_min_temp = amdgpu_fan_metrics.get("Min RPM").map_or_else(
-                || {
-                    if !amd_sleep_skip && !args.get_flag("force-amd") {
-                        println!("Error getting Min RPM info for amd. This could be due to a missing sensor for your GPU model.");
-                    }
-                    None
-                },
-                |&value| Some(value),
-            );
-
-            amd_max_temp = amdgpu_fan_metrics.get("Max RPM").map_or_else(
-                || {
-                    if !amd_sleep_skip && !args.get_flag("force-amd") {
-                        println!("Error getting Max RPM info for amd. This could be due to a missing sensor for your GPU model.");
-                    }
-                    None
-                },
-                |&value| Some(value),
-            );
-
-            if !args.get_flag("force-amd")
-                && (amd_current_rpm.is_none() || amd_fan_speed_percentage.is_none() || amd_junction_temp.is_none() || amd_memory_temp.is_none() || amd_min_temp.is_none() || amd_max_temp.is_none())
-                && !amd_sleep_skip
-            {
-                sleep(5.0);
-                amd_sleep_skip = true;
+        readouts.clear();
+        let mut missing_sensor = false;
+
+        // The driver resets the fans to automatic on resume, so forget what we last wrote
+        let resumed = resume_detector.check();
+        if let Some(suspended) = resumed {
+            notice!("Resumed after {:.0}s of suspend, re-applying fan control", suspended);
+        }
+
+        // Pick up whatever the control socket changed since the last tick
+        let (profile, speed_override, paused, reloaded) = {
+            let mut control = control.lock().unwrap_or_else(PoisonError::into_inner);
+            (control.profile.clone(), control.active_override(), control.paused, control.reloaded.take())
+        };
+        if let Some(mut reloaded) = reloaded {
+            // The command line still wins over the reloaded file
+            apply_cli_overrides(&mut reloaded, &args)?;
+            // The progress of the old rules means nothing for the new ones
+            if reloaded.emergency != config.emergency {
+                for gpu in gpus.lock().unwrap_or_else(PoisonError::into_inner).iter_mut() {
+                    reset_emergency_state(&config.emergency, &mut gpu.emergency, gpu.backend.as_mut());
+                }
             }
-        } else {
-            eprintln!("Error: Unknown GPU or no GPU found");
-            exit(1);
+            config = reloaded;
+        }
+        if paused != was_paused {
+            info!("{}", if paused { "Control paused, fans handed back to the driver" } else { "Control resumed" });
         }
-        let speed_output = diff_func(temp);
-        if args.get_flag("no-tui") {
-            if speed_output != Into::<u8>::into(temp_capture_call) {
-                //TODO Added if statement here for later amd gpu intergration
-                if gpu_manufacturer == 0 {
-                    for faninc in 0..FAN_AMOUNT {
-                        Command::new("nvidia-settings").arg("-a").arg(&format!("GPUTargetFanSpeed[fan:{}]={}", faninc, speed_output)).output().expect("nvidia-settings command failed to execute");
+
+        let mut replay_finished = true;
+        let mut fatal: Option<Error> = None;
+        let tick = Tick { profile: &profile, speed_override, paused, resumed: resumed.is_some() };
+
+        // Read every GPU and write a new speed to it if the curve asks for one
+        for gpu in gpus.lock().unwrap_or_else(PoisonError::into_inner).iter_mut() {
+            let timestamp = unix_timestamp();
+            let read_started = Instant::now();
+            // Transient errors get a few quick retries, fatal ones stop fan control altogether
+            let metrics = match config.retry.run(|| gpu.backend.read_metrics()) {
+                Err(e) if e.is_fatal() => {
+                    fatal = Some(e);
+                    break;
+                }
+                metrics => metrics,
+            };
+
+            // Only amdgpu exposes the extra sensors, so only warn about them there
+            if backend_kind == BackendKind::Amd {
+                for sensor in metrics.as_ref().map(GpuMetrics::missing_sensors).unwrap_or_default() {
+                    missing_sensor = true;
+                    if !sleep_skip && !args.get_flag("force-amd") {
+                        warning!(gpu = gpu.backend.id(); "Error getting {} info for amd {}. This could be due to a missing sensor for your GPU model.", sensor, gpu.backend.label());
                     }
-                } else {
-                    eprintln!("Error: Unknown GPU or no GPU found");
-                    exit(1);
                 }
             }
-            temp_capture_call = speed_output;
-        } else {
-            let rgb_value_temp = rgb_temp(&rgb_array, temp);
-            let rgb_value_speed_output = rgb_temp(&rgb_array, speed_output);
-            let gpu_temp_str: String = if args.get_flag("fahrenheit-id") { format!("Gpu temp: {}°F", celcius_to_fahrenheit(temp)) } else { format!("Gpu temp: {}°C", temp) };
-            let fan_speed_output_str = format!("Current fan speed: {}%", speed_output);
-            let skip = format!("Skipped execution as speed has not changed from {}", speed_output);
-            let skip_changed = format!("Changed Speed to {}", speed_output);
 
+            match control_gpu(gpu, &config, &tick, metrics, read_started, timestamp) {
+                Ok(readout) => {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.record(&readout);
+                    }
+                    readouts.push(readout);
+                }
+                Err(e) => {
+                    fatal = Some(e);
+                    break;
+                }
+            }
+
+            replay_finished &= gpu.backend.finished();
+        }
+
+        was_paused = paused;
+        control.lock().unwrap_or_else(PoisonError::into_inner).readouts = readouts.clone();
+        if let Some(exporter) = &exporter {
+            exporter.update(&readouts, &profile.name);
+        }
+        if let Some(csv_logger) = csv_logger.as_mut() {
+            csv_logger.log(&readouts);
+        }
+        if jsonl_output {
+            let mut stdout = io::stdout().lock();
+            for readout in &readouts {
+                let _ = writeln!(stdout, "{}", readout_to_json(readout));
+            }
+        }
+
+        // The restore guard hands every fan back to the driver on the way out
+        if let Some(e) = fatal {
+            return Err(e);
+        }
+
+        if !args.get_flag("force-amd") && missing_sensor && !sleep_skip {
+            sleep(5.0);
+            sleep_skip = true;
+        }
+
+        if !no_tui {
             // Hide the cursor
             print!("\x1B[?25l");
 
@@ -497,12 +860,6 @@ fn main() {
                     width = size.0 as usize;
                     height = size.1 as usize;
 
-                    // Calculate the center position
-                    temp_center = (width - gpu_temp_str.len()) / 2;
-                    speed_output_center = (width - fan_speed_output_str.len()) / 2;
-                    skip_center = (width - skip.len()) / 2;
-                    skip_changed_center = (width - skip_changed.len()) / 2;
-
                     // Calculate vertical centering
                     vertical_center = height / 2;
                 }
@@ -510,79 +867,24 @@ fn main() {
                 // Clear the terminal before printing (optional)
                 print!("\x1B[2J\x1B[1;1H");
 
-                // Debug print statements
-                // println!("gpu_temp_str: {}", gpu_temp_str.len());
-                // println!("Width: {}", width);
-                // println!("Height: {}", height);
-                // println!("Temperature Center: {}", temp_center);
-                // println!("Speed Output Center: {}", speed_output_center);
-                // println!("Skip Center: {}", skip_center);
-                // println!("Skip Changed Center: {}", skip_changed_center);
-                // println!("Vertical Center: {}", vertical_center);
-
                 // Move the cursor to the desired position
                 print!("\x1B[{};H", vertical_center);
 
-                // Print the formatted output at the calculated center positions
-                println!("{: >width$}", gpu_temp_str.truecolor(rgb_value_temp.0, rgb_value_temp.1, rgb_value_temp.2), width = temp_center + gpu_temp_str.len());
-
-                if gpu_manufacturer == 1 {
-                    // Calculate junction pos
-                    print_centered_colored_string(
-                        width,
-                        "Junction/hotspot: {}°C",
-                        amd_junction_temp,
-                        Some(50.0),
-                        Some(95.0),
-                        &rgb_array,
-                        args.get_flag("fahrenheit-id"),
-                        Some("Junction/hotspot: {}°F"),
-                    );
-
-                    // Calculate Vram/Memory pos
-                    print_centered_colored_string(width, "Memory/vram temp: {}°C", amd_memory_temp, Some(60.0), Some(90.0), &rgb_array, args.get_flag("fahrenheit-id"), Some("Memory/vram temp: {}°F"));
-
-                    // Calculate rpm pos
-                    print_centered_colored_string(width, "Current fan RPM: {}", amd_current_rpm, amd_min_temp, amd_max_temp, &rgb_array, false, Some(""));
-
-                    // Calculate fanspeed pos
-                    if let Some(fan_speed_percentage) = amd_fan_speed_percentage {
-                        print_centered_colored_string(width, "Current fan speed: {}%", Some(fan_speed_percentage as f32), Some(30.0), Some(85.0), &rgb_array, false, Some(""));
-                    }
-                } else {
-                    println!(
-                        "{: >width$}",
-                        fan_speed_output_str.truecolor(rgb_value_speed_output.0, rgb_value_speed_output.1, rgb_value_speed_output.2),
-                        width = speed_output_center + fan_speed_output_str.len()
-                    );
-                }
-                //TODO Added if statement here for later amd gpu intergration
-                if gpu_manufacturer == 0 {
-                    if speed_output == Into::<u8>::into(temp_capture_call) {
-                        println!("{: >width$}", skip, width = skip_center + skip.len());
-                    } else {
-                        println!("{: >width$}", skip_changed, width = skip_changed_center + skip_changed.len());
-                        for faninc in 0..FAN_AMOUNT {
-                            Command::new("nvidia-settings")
-                                .arg("-a")
-                                .arg(&format!(
-                                    "
-                GPUTargetFanSpeed[fan:{}]={}",
-                                    faninc, speed_output
-                                ))
-                                .output()
-                                .expect("nvidia-settings command failed to execute");
-                        }
-                    }
-                    temp_capture_call = speed_output;
-                } else if gpu_manufacturer == 1 {
-                    // TODO: Here for possible fan setting
-                } else {
-                    eprintln!("Error: Unknown GPU or no GPU found");
-                    exit(1);
+                for readout in &readouts {
+                    print_gpu_readout(width, readout, readouts.len() > 1, &rgb_array, args.get_flag("fahrenheit-id"));
                 }
             }
         }
+
+        // Stop once every replayed trace has been played back completely
+        if replay_finished {
+            if !no_tui {
+                print!("\x1B[?25h");
+            }
+            report("Replay finished");
+            return Ok(());
+        }
+
         sleep(refresh_time);
     }
 }
@@ -0,0 +1,531 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::sync::PoisonError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::config::{string_key, typed_key};
+use crate::daemon::SharedControlState;
+use crate::logging::{info, warning};
+use crate::sleep;
+use crate::trace::readout_to_json;
+
+/// MQTT packet types, already shifted into the upper nibble of the fixed header
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const PINGREQ: u8 = 0xC0;
+const DISCONNECT: u8 = 0xE0;
+
+/// Home Assistant node id of the profile select, GPU node ids start with it
+const NODE_ID: &str = "rust_gpu_fan_control";
+
+/// Seconds without traffic before the broker drops us, we ping at half of it
+const KEEP_ALIVE: u16 = 60;
+
+/// Where to publish and how to log in.
+///
+/// Readings are published every `interval` seconds to `<topic_prefix>/<gpu>/state`,
+/// Home Assistant discovery messages go to `<discovery_prefix>/...` and profile names
+/// published to `<topic_prefix>/profile/set` switch the profile.
+#[derive(Clone, Debug)]
+pub struct MqttSettings {
+    pub host: Option<String>,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub client_id: String,
+    pub topic_prefix: String,
+    pub discovery_prefix: String,
+    pub interval: f32,
+}
+
+impl Default for MqttSettings {
+    fn default() -> MqttSettings {
+        MqttSettings {
+            host: None,
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: String::from("rust-gpu-fan-control"),
+            topic_prefix: String::from("rust-gpu-fan-control"),
+            discovery_prefix: String::from("homeassistant"),
+            interval: 10.0,
+        }
+    }
+}
+
+impl MqttSettings {
+    /// Reads the broker and topic settings from the `mqtt` object of the config file, unset keys fall back to the defaults.
+    ///
+    /// ```json
+    /// "mqtt": { "host": "localhost", "port": 1883, "username": "fan", "password": "secret", "interval": 10 }
+    /// ```
+    pub fn from_json(value: &Value) -> Result<MqttSettings, String> {
+        let mut settings = MqttSettings::default();
+        let string = |key: &str| string_key(value, "mqtt", key);
+
+        // An IPv6 address may be given in brackets as in a URL
+        settings.host = string("host")?.map(|host| host.trim_start_matches('[').trim_end_matches(']').to_string());
+        if let Some(port) = typed_key(value, "mqtt", "port", "must be a port number", |port| port.as_u64().and_then(|port| u16::try_from(port).ok()))? {
+            settings.port = port;
+        }
+        settings.username = string("username")?;
+        settings.password = string("password")?;
+        if let Some(client_id) = string("client_id")? {
+            settings.client_id = client_id;
+        }
+        if let Some(topic_prefix) = string("topic_prefix")? {
+            settings.topic_prefix = topic_prefix;
+        }
+        if let Some(discovery_prefix) = string("discovery_prefix")? {
+            settings.discovery_prefix = discovery_prefix;
+        }
+        if let Some(interval) = typed_key(value, "mqtt", "interval", "must be a positive number of seconds", |interval| interval.as_f64().filter(|interval| *interval > 0.0))? {
+            settings.interval = interval as f32;
+        }
+
+        Ok(settings)
+    }
+
+    fn availability_topic(&self) -> String {
+        format!("{}/status", self.topic_prefix)
+    }
+
+    fn profile_topic(&self) -> String {
+        format!("{}/profile", self.topic_prefix)
+    }
+
+    fn command_topic(&self) -> String {
+        format!("{}/profile/set", self.topic_prefix)
+    }
+
+    fn state_topic(&self, gpu_id: &str) -> String {
+        format!("{}/{}/state", self.topic_prefix, gpu_id)
+    }
+}
+
+/// Splits `--mqtt HOST[:PORT]` into the host and the port if one is given.
+///
+/// IPv6 addresses take a port only in brackets (`[::1]:1883`), a bare one (`::1`) is all host.
+pub fn parse_broker(broker: &str) -> Result<(String, Option<u16>), String> {
+    let invalid = || format!("Invalid MQTT broker \"{}\", expected HOST, HOST:PORT or [IPV6]:PORT", broker);
+    let port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+
+    if let Some(bracketed) = broker.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']').ok_or_else(invalid)?;
+        return match rest {
+            "" => Ok((host.to_string(), None)),
+            _ => Ok((host.to_string(), Some(port(rest.strip_prefix(':').ok_or_else(invalid)?)?))),
+        };
+    }
+    if broker.parse::<IpAddr>().is_ok() {
+        return Ok((broker.to_string(), None));
+    }
+    match broker.split_once(':') {
+        Some((host, rest)) if !rest.contains(':') => Ok((host.to_string(), Some(port(rest)?))),
+        Some(_) => Err(invalid()),
+        None => Ok((broker.to_string(), None)),
+    }
+}
+
+/// `host:port` for messages, with IPv6 addresses in brackets.
+fn broker_address(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Appends a length prefixed UTF-8 string as MQTT encodes them.
+fn push_string(packet: &mut Vec<u8>, text: &str) {
+    packet.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    packet.extend_from_slice(text.as_bytes());
+}
+
+/// Builds a packet from its first header byte and body, encoding the remaining length.
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Takes the first whole packet off the front of `buffer`, returning its first header byte and body.
+///
+/// `None` while the packet is still incomplete, the rest arrives with later reads.
+///
+/// # Errors
+/// Fails if the remaining length doesn't end within the four bytes MQTT allows.
+fn take_packet(buffer: &mut Vec<u8>) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut length = 0usize;
+    let mut header_length = 1;
+    loop {
+        let Some(&byte) = buffer.get(header_length) else { return Ok(None) };
+        length |= ((byte & 0x7F) as usize) << (7 * (header_length - 1));
+        header_length += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if header_length > 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the broker sent a malformed remaining length"));
+        }
+    }
+
+    if buffer.len() < header_length + length {
+        return Ok(None);
+    }
+    let header = buffer[0];
+    let body = buffer[header_length..header_length + length].to_vec();
+    buffer.drain(..header_length + length);
+    Ok(Some((header, body)))
+}
+
+/// A connection to the broker speaking just enough MQTT 3.1.1 for QoS 0.
+struct MqttConnection {
+    stream: TcpStream,
+    last_sent: Instant,
+    /// Bytes received but not yet making up a whole packet
+    buffer: Vec<u8>,
+}
+
+impl MqttConnection {
+    /// Connects and logs in, with a retained "offline" will on the availability topic.
+    fn connect(settings: &MqttSettings, host: &str) -> io::Result<MqttConnection> {
+        let stream = TcpStream::connect((host, settings.port))?;
+        // Reads only wait this long so the session can go on publishing between commands
+        stream.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+        let mut flags = 0x02 | 0x04 | 0x20; // Clean session, will flag, retained will
+        let mut body = Vec::new();
+        push_string(&mut body, "MQTT");
+        body.push(4); // Protocol level of MQTT 3.1.1
+        if settings.username.is_some() {
+            flags |= 0x80;
+        }
+        if settings.password.is_some() {
+            flags |= 0x40;
+        }
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+        push_string(&mut body, &settings.client_id);
+        push_string(&mut body, &settings.availability_topic());
+        push_string(&mut body, "offline");
+        if let Some(username) = &settings.username {
+            push_string(&mut body, username);
+        }
+        if let Some(password) = &settings.password {
+            push_string(&mut body, password);
+        }
+
+        let mut connection = MqttConnection { stream, last_sent: Instant::now(), buffer: Vec::new() };
+        connection.send(&packet(CONNECT, &body))?;
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let (header, body) = loop {
+            if let Some(packet) = connection.poll_packet()? {
+                break packet;
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the broker did not answer within 10s"));
+            }
+        };
+        if header & 0xF0 != CONNACK || body.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the broker did not answer with CONNACK"));
+        }
+        if body[1] != 0 {
+            let reason = match body[1] {
+                1 => "unacceptable protocol version",
+                2 => "client id rejected",
+                3 => "server unavailable",
+                4 => "bad username or password",
+                5 => "not authorized",
+                _ => "unknown reason",
+            };
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("the broker refused the connection: {}", reason)));
+        }
+        Ok(connection)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, payload: &str, retain: bool) -> io::Result<()> {
+        let mut body = Vec::new();
+        push_string(&mut body, topic);
+        body.extend_from_slice(payload.as_bytes());
+        self.send(&packet(PUBLISH | retain as u8, &body))
+    }
+
+    fn subscribe(&mut self, topic: &str) -> io::Result<()> {
+        let mut body = 1u16.to_be_bytes().to_vec(); // Packet id
+        push_string(&mut body, topic);
+        body.push(0); // QoS 0
+        self.send(&packet(SUBSCRIBE, &body))
+    }
+
+    /// Waits briefly for the next packet, `None` if no whole packet arrived in time.
+    ///
+    /// A packet cut off by the read timeout stays buffered until the rest of it arrives.
+    fn poll_packet(&mut self) -> io::Result<Option<(u8, Vec<u8>)>> {
+        if let Some(packet) = take_packet(&mut self.buffer)? {
+            return Ok(Some(packet));
+        }
+
+        let mut chunk = [0u8; 4096];
+        match self.stream.read(&mut chunk) {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "the broker closed the connection")),
+            Ok(read) => {
+                self.buffer.extend_from_slice(&chunk[..read]);
+                take_packet(&mut self.buffer)
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Node id Home Assistant groups the entities of a GPU under, only `[a-zA-Z0-9_-]` is allowed.
+fn node_id(gpu_id: &str) -> String {
+    format!("{}_{}", NODE_ID, gpu_id).chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+/// Home Assistant discovery messages for one GPU, as `(topic, payload)`.
+fn discovery_messages(settings: &MqttSettings, gpu_id: &str, label: &str) -> Vec<(String, Value)> {
+    let node = node_id(gpu_id);
+    let device = json!({ "identifiers": [node], "name": format!("GPU fan control {}", label), "model": label, "manufacturer": "rust-gpu-fan-control" });
+    let state_topic = settings.state_topic(gpu_id);
+
+    let sensors = [
+        ("temp", "Temperature", Some("temperature"), "°C"),
+        ("junction_temp", "Junction temperature", Some("temperature"), "°C"),
+        ("memory_temp", "Memory temperature", Some("temperature"), "°C"),
+        ("fan_speed", "Fan speed", None, "%"),
+        ("speed", "Target fan speed", None, "%"),
+        ("fan_rpm", "Fan RPM", None, "RPM"),
+    ];
+    let mut messages: Vec<(String, Value)> = sensors
+        .iter()
+        .map(|(key, name, device_class, unit)| {
+            let mut config = json!({
+                "name": name,
+                "unique_id": format!("{}_{}", node, key),
+                "state_topic": state_topic,
+                "value_template": format!("{{{{ value_json.{} }}}}", key),
+                "unit_of_measurement": unit,
+                "state_class": "measurement",
+                "availability_topic": settings.availability_topic(),
+                "device": device,
+            });
+            if let Some(device_class) = device_class {
+                config["device_class"] = json!(device_class);
+            }
+            (format!("{}/sensor/{}/{}/config", settings.discovery_prefix, node, key), config)
+        })
+        .collect();
+
+    messages.push((
+        format!("{}/binary_sensor/{}/failsafe/config", settings.discovery_prefix, node),
+        json!({
+            "name": "Failsafe",
+            "unique_id": format!("{}_failsafe", node),
+            "state_topic": state_topic,
+            "value_template": "{{ 'ON' if value_json.failsafe else 'OFF' }}",
+            "device_class": "problem",
+            "availability_topic": settings.availability_topic(),
+            "device": device,
+        }),
+    ));
+    messages
+}
+
+/// Home Assistant discovery message of the profile select, shared by all GPUs, as `(topic, payload)`.
+fn profile_discovery_message(settings: &MqttSettings, profiles: &[String]) -> (String, Value) {
+    let node = NODE_ID;
+    let config = json!({
+        "name": "Fan profile",
+        "unique_id": format!("{}_profile", node),
+        "state_topic": settings.profile_topic(),
+        "command_topic": settings.command_topic(),
+        "options": profiles,
+        "availability_topic": settings.availability_topic(),
+        "device": { "identifiers": [node], "name": "GPU fan control", "manufacturer": "rust-gpu-fan-control" },
+    });
+    (format!("{}/select/{}/profile/config", settings.discovery_prefix, node), config)
+}
+
+/// Publishes until the connection breaks, applying profile switches from the command topic.
+fn run_session(connection: &mut MqttConnection, settings: &MqttSettings, state: &SharedControlState) -> io::Result<()> {
+    connection.publish(&settings.availability_topic(), "online", true)?;
+    connection.subscribe(&settings.command_topic())?;
+
+    // GPUs and profiles the discovery messages were last published for, both can change with a reload
+    let mut discovered_gpus: Vec<String> = Vec::new();
+    let mut discovered_profiles: Vec<String> = Vec::new();
+    let mut published_profile = String::new();
+    let mut last_publish: Option<Instant> = None;
+
+    loop {
+        let (readouts, profile, profiles) = {
+            let state = state.lock().unwrap_or_else(PoisonError::into_inner);
+            (state.readouts.clone(), state.profile.name.clone(), state.profiles.iter().map(|profile| profile.name.clone()).collect::<Vec<_>>())
+        };
+
+        let gpu_ids: Vec<String> = readouts.iter().map(|readout| readout.id.clone()).collect();
+        if !gpu_ids.is_empty() && (gpu_ids != discovered_gpus || profiles != discovered_profiles) {
+            let messages = readouts.iter().flat_map(|readout| discovery_messages(settings, &readout.id, &readout.label));
+            for (topic, config) in messages.chain([profile_discovery_message(settings, &profiles)]) {
+                connection.publish(&topic, &config.to_string(), true)?;
+            }
+            (discovered_gpus, discovered_profiles) = (gpu_ids, profiles);
+        }
+
+        if profile != published_profile {
+            connection.publish(&settings.profile_topic(), &profile, true)?;
+            published_profile = profile;
+        }
+
+        if last_publish.is_none_or(|last_publish| last_publish.elapsed().as_secs_f32() >= settings.interval) && !readouts.is_empty() {
+            for readout in &readouts {
+                connection.publish(&settings.state_topic(&readout.id), &readout_to_json(readout).to_string(), false)?;
+            }
+            last_publish = Some(Instant::now());
+        }
+
+        if connection.last_sent.elapsed().as_secs() >= KEEP_ALIVE as u64 / 2 {
+            connection.send(&[PINGREQ, 0])?;
+        }
+
+        // Besides answers to pings and subscriptions only the command topic sends us anything
+        if let Some((header, body)) = connection.poll_packet()? {
+            if header & 0xF0 == PUBLISH && body.len() >= 2 {
+                let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                // QoS 1 and 2 messages carry a packet id after the topic
+                let payload_start = 2 + topic_length + if header & 0x06 != 0 { 2 } else { 0 };
+                let topic = String::from_utf8_lossy(body.get(2..2 + topic_length).unwrap_or_default());
+                let payload = String::from_utf8_lossy(body.get(payload_start..).unwrap_or_default()).trim().to_string();
+                if topic == settings.command_topic() {
+                    if let Err(e) = state.lock().unwrap_or_else(PoisonError::into_inner).set_profile(&payload) {
+                        warning!("MQTT: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Starts publishing to the broker in the background, reconnecting whenever the connection drops.
+///
+/// Does nothing if no broker is configured.
+pub fn start_mqtt(settings: MqttSettings, state: SharedControlState) {
+    let Some(host) = settings.host.clone() else { return };
+    let address = broker_address(&host, settings.port);
+
+    thread::spawn(move || {
+        let mut delay = 1.0;
+        loop {
+            match MqttConnection::connect(&settings, &host) {
+                Ok(mut connection) => {
+                    info!("MQTT: connected to {}", address);
+                    delay = 1.0;
+                    if let Err(e) = run_session(&mut connection, &settings, &state) {
+                        warning!("MQTT: lost the connection to {}: {}", address, e);
+                    }
+                    let _ = connection.send(&[DISCONNECT, 0]);
+                }
+                Err(e) => warning!("MQTT: failed to connect to {}: {}, retrying in {:.0}s", address, e, delay),
+            }
+            sleep(delay);
+            delay = (delay * 2.0).min(60.0);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_encodes_the_remaining_length() {
+        for (length, encoded) in [(0, vec![0x00]), (127, vec![0x7F]), (128, vec![0x80, 0x01]), (16_383, vec![0xFF, 0x7F]), (16_384, vec![0x80, 0x80, 0x01]), (2_097_152, vec![0x80, 0x80, 0x80, 0x01])] {
+            let packet = packet(PUBLISH, &vec![0; length]);
+            assert_eq!(packet[0], PUBLISH);
+            assert_eq!(packet[1..=encoded.len()], encoded, "length {}", length);
+            assert_eq!(packet.len(), 1 + encoded.len() + length);
+        }
+    }
+
+    #[test]
+    fn take_packet_decodes_what_packet_encodes() {
+        for length in [0, 5, 127, 128, 300, 16_384] {
+            let body: Vec<u8> = (0..length).map(|byte| byte as u8).collect();
+            let mut buffer = packet(PUBLISH, &body);
+            assert_eq!(take_packet(&mut buffer).unwrap(), Some((PUBLISH, body)));
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn take_packet_waits_for_the_rest_of_a_packet() {
+        let whole = packet(PUBLISH, &[7; 200]);
+        for cut in 0..whole.len() {
+            let mut buffer = whole[..cut].to_vec();
+            assert_eq!(take_packet(&mut buffer).unwrap(), None, "cut after {} bytes", cut);
+            assert_eq!(buffer.len(), cut);
+            buffer.extend_from_slice(&whole[cut..]);
+            assert_eq!(take_packet(&mut buffer).unwrap(), Some((PUBLISH, vec![7; 200])));
+        }
+    }
+
+    #[test]
+    fn take_packet_leaves_the_next_packet_buffered() {
+        let mut buffer = packet(CONNACK, &[0, 0]);
+        buffer.extend_from_slice(&packet(PUBLISH, b"next")[..3]);
+        assert_eq!(take_packet(&mut buffer).unwrap(), Some((CONNACK, vec![0, 0])));
+        assert_eq!(buffer, [PUBLISH, 4, b'n']);
+        assert_eq!(take_packet(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn take_packet_rejects_overlong_remaining_lengths() {
+        let mut buffer = vec![PUBLISH, 0x80, 0x80, 0x80, 0x80, 0x01];
+        assert!(take_packet(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn parse_broker_handles_ipv6() {
+        assert_eq!(parse_broker("localhost"), Ok((String::from("localhost"), None)));
+        assert_eq!(parse_broker("broker.lan:8883"), Ok((String::from("broker.lan"), Some(8883))));
+        assert_eq!(parse_broker("192.168.1.2:1883"), Ok((String::from("192.168.1.2"), Some(1883))));
+        assert_eq!(parse_broker("::1"), Ok((String::from("::1"), None)));
+        assert_eq!(parse_broker("[::1]"), Ok((String::from("::1"), None)));
+        assert_eq!(parse_broker("[fd00::2]:1884"), Ok((String::from("fd00::2"), Some(1884))));
+        for broker in ["host:", "host:port", "host:70000", "[::1", "[::1]1883", "fd00::2:port"] {
+            assert!(parse_broker(broker).is_err(), "{:?} was accepted", broker);
+        }
+    }
+
+    #[test]
+    fn broker_address_brackets_ipv6() {
+        assert_eq!(broker_address("::1", 1883), "[::1]:1883");
+        assert_eq!(broker_address("localhost", 1883), "localhost:1883");
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::{Arc, Mutex, PoisonError};
+
+use serde_json::{json, Value};
+
+use crate::backend::{GpuBackend, GpuMetrics, Restorer};
+use crate::calculations::{assign_nvidia_attribute, cleanup_nvidia, get_current_nvidia_fan_speed, get_current_nvidia_temp, get_nvidia_power_limit, query_nvidia_attribute, set_nvidia_fan_speed, set_nvidia_power_limit};
+use crate::compile_flag_helper::FAN_AMOUNT;
+use crate::error::Error;
+use crate::logging::{error, warning};
+use crate::state::{clear_original_state, load_or_save_original_state};
+use crate::GPU_NUMBER;
+
+/// Fan control mode and per fan target speeds of the GPU before we took over.
+#[derive(Clone, Debug)]
+struct NvidiaFanState {
+    /// `GPUFanControlState`, 1 when the fans were under manual control
+    control_state: u32,
+    /// `GPUTargetFanSpeed` of every fan, `None` if it couldn't be read
+    speeds: Vec<Option<u32>>,
+}
+
+impl NvidiaFanState {
+    /// Reads the current fan state through `nvidia-settings`.
+    fn capture() -> NvidiaFanState {
+        NvidiaFanState {
+            control_state: query_nvidia_attribute(&format!("[gpu:{}]/GPUFanControlState", GPU_NUMBER)).unwrap_or(0),
+            speeds: (0..FAN_AMOUNT).map(|faninc| query_nvidia_attribute(&format!("[fan:{}]/GPUTargetFanSpeed", faninc))).collect(),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({ "control_state": self.control_state, "speeds": self.speeds })
+    }
+
+    fn from_json(value: &Value) -> Option<NvidiaFanState> {
+        Some(NvidiaFanState {
+            control_state: value.get("control_state")?.as_u64()? as u32,
+            speeds: value.get("speeds")?.as_array()?.iter().map(|speed| speed.as_u64().map(|speed| speed as u32)).collect(),
+        })
+    }
+
+    /// Puts the fans back exactly as they were: manual speeds are re-applied, otherwise
+    /// the driver gets automatic control back.
+    fn restore(&self) {
+        if self.control_state != 1 {
+            cleanup_nvidia();
+            return;
+        }
+
+        for (faninc, speed) in self.speeds.iter().enumerate() {
+            if let Some(speed) = speed {
+                assign(&format!("[fan:{}]/GPUTargetFanSpeed={}", faninc, speed));
+            }
+        }
+        assign(&format!("[gpu:{}]/GPUFanControlState=1", GPU_NUMBER));
+    }
+}
+
+/// Runs `nvidia-settings -a`, only reporting failures since it is used while exiting.
+fn assign(assignment: &str) {
+    if let Err(e) = assign_nvidia_attribute(assignment) {
+        error!("{}", e);
+    }
+}
+
+/// NVIDIA GPU controlled through `nvidia-smi` and `nvidia-settings`.
+pub struct NvidiaGpu {
+    original: NvidiaFanState,
+    /// Power limit in watts from before it was lowered, shared with the restorer
+    original_power_limit: Arc<Mutex<Option<f32>>>,
+}
+
+impl NvidiaGpu {
+    /// Snapshots the fan state so it can be restored on exit, or recovers the snapshot a
+    /// previous run left behind if it never got to restore it.
+    pub fn new() -> NvidiaGpu {
+        let id = format!("nvidia-{}", GPU_NUMBER);
+        let (original, recovered) = load_or_save_original_state(&id, NvidiaFanState::capture().to_json());
+        let original = NvidiaFanState::from_json(&original).unwrap_or_else(NvidiaFanState::capture);
+
+        if recovered {
+            warning!("The previous run did not restore the fans of NVIDIA gpu:{}, restoring the state it recorded", GPU_NUMBER);
+            original.restore();
+        }
+
+        NvidiaGpu { original, original_power_limit: Arc::new(Mutex::new(None)) }
+    }
+}
+
+impl GpuBackend for NvidiaGpu {
+    fn id(&self) -> String {
+        format!("nvidia-{}", GPU_NUMBER)
+    }
+
+    fn label(&self) -> String {
+        format!("NVIDIA gpu:{}", GPU_NUMBER)
+    }
+
+    fn read_metrics(&mut self) -> Result<GpuMetrics, Error> {
+        let temp = get_current_nvidia_temp()?;
+        Ok(GpuMetrics { temp: Some(temp as f32), fan_speed: get_current_nvidia_fan_speed().map(|speed| speed as f32), ..Default::default() })
+    }
+
+    fn set_fan_speed(&mut self, speed: u8) -> Result<(), Error> {
+        set_nvidia_fan_speed(speed)
+    }
+
+    fn set_power_limit(&mut self, watts: Option<u32>) -> Result<(), Error> {
+        let mut original_power_limit = self.original_power_limit.lock().unwrap_or_else(PoisonError::into_inner);
+        match watts {
+            Some(watts) => {
+                if original_power_limit.is_none() {
+                    *original_power_limit = Some(get_nvidia_power_limit().ok_or_else(|| Error::Transient(String::from("Failed to read the current power limit")))?);
+                }
+                set_nvidia_power_limit(watts as f32)
+            }
+            None => match *original_power_limit {
+                Some(watts) => set_nvidia_power_limit(watts).map(|_| *original_power_limit = None),
+                None => Ok(()),
+            },
+        }
+    }
+
+    fn pause(&mut self) {
+        self.original.restore();
+    }
+
+    fn restorer(&self) -> Restorer {
+        let id = self.id();
+        let original = self.original.clone();
+        let original_power_limit = Arc::clone(&self.original_power_limit);
+        Arc::new(move || {
+            original.restore();
+            clear_original_state(&id);
+
+            if let Some(watts) = *original_power_limit.lock().unwrap_or_else(PoisonError::into_inner) {
+                if let Err(e) = set_nvidia_power_limit(watts) {
+                    error!("{}", e);
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,116 @@
+use serde_json::Value;
+
+use crate::calculations::{curve_speed, HIGH_TEMP_INCREMENTS};
+use crate::SPEED;
+
+/// A named fan curve.
+///
+/// Profiles use the same model as [`crate::calculations::curve_speed`]: the closest value
+/// in `speeds` is picked for the current temperature and the matching entry of
+/// `increments` is added on top of it.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+    pub speeds: Vec<u8>,
+    pub increments: Vec<(u8, i8)>,
+}
+
+impl Profile {
+    /// Fan speed in percent this profile asks for at the given temperature.
+    pub fn speed_for(&self, temp: u8) -> u8 {
+        curve_speed(&self.speeds, &self.increments, temp)
+    }
+
+    /// Parses a profile from its JSON object in the config file.
+    ///
+    /// ```json
+    /// { "speeds": [10, 20, 30, 40, 50, 60, 70, 80, 90, 100], "increments": [[70, 2], [85, 15]] }
+    /// ```
+    /// Missing keys fall back to the values of the `default` profile.
+    pub fn from_json(name: &str, value: &Value) -> Result<Profile, String> {
+        let mut profile = Profile { name: name.to_string(), ..Profile::default() };
+
+        if let Some(speeds) = value.get("speeds") {
+            let speeds = speeds.as_array().ok_or_else(|| format!("Profile \"{}\": \"speeds\" must be an array", name))?;
+            profile.speeds = speeds
+                .iter()
+                .map(|speed| speed.as_u64().filter(|speed| *speed <= 100).map(|speed| speed as u8))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| format!("Profile \"{}\": \"speeds\" must only contain numbers from 0 to 100", name))?;
+            if profile.speeds.is_empty() {
+                return Err(format!("Profile \"{}\": \"speeds\" must not be empty", name));
+            }
+        }
+
+        if let Some(increments) = value.get("increments") {
+            let increments = increments.as_array().ok_or_else(|| format!("Profile \"{}\": \"increments\" must be an array", name))?;
+            profile.increments = increments
+                .iter()
+                .map(|pair| {
+                    let temp = pair.get(0)?.as_u64().filter(|temp| *temp <= u8::MAX as u64)?;
+                    let increment = pair.get(1)?.as_i64().filter(|increment| (-100..=100).contains(increment))?;
+                    Some((temp as u8, increment as i8))
+                })
+                .collect::<Option<Vec<(u8, i8)>>>()
+                .ok_or_else(|| format!("Profile \"{}\": \"increments\" must be [temperature, increment] pairs", name))?;
+        }
+
+        Ok(profile)
+    }
+}
+
+impl Default for Profile {
+    /// The SPEED array with the HIGH_TEMP_INCREMENTS on top.
+    fn default() -> Profile {
+        Profile { name: String::from("default"), speeds: SPEED.to_vec(), increments: HIGH_TEMP_INCREMENTS.to_vec() }
+    }
+}
+
+/// Profiles that are always available, entries in the config file with the same name replace them.
+pub fn builtin_profiles() -> Vec<Profile> {
+    vec![
+        Profile::default(),
+        Profile { name: String::from("quiet"), speeds: SPEED.to_vec(), increments: vec![(40, -10), (60, -8), (75, -4), (80, 0), (84, 10), (86, 15)] },
+        Profile { name: String::from("performance"), speeds: SPEED.to_vec(), increments: vec![(40, 10), (60, 15), (70, 20), (80, 25)] },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn from_json_falls_back_to_the_default_profile() {
+        let profile = Profile::from_json("custom", &json!({})).unwrap();
+        assert_eq!(profile.name, "custom");
+        assert_eq!(profile.speeds, SPEED.to_vec());
+        assert_eq!(profile.increments, HIGH_TEMP_INCREMENTS.to_vec());
+    }
+
+    #[test]
+    fn from_json_reads_speeds_and_increments() {
+        let profile = Profile::from_json("silent", &json!({ "speeds": [20, 40, 60], "increments": [[50, -5], [80, 20]] })).unwrap();
+        assert_eq!(profile.speeds, vec![20, 40, 60]);
+        assert_eq!(profile.increments, vec![(50, -5), (80, 20)]);
+        assert_eq!(profile.speed_for(40), 40);
+        assert_eq!(profile.speed_for(55), 55);
+        assert_eq!(profile.speed_for(85), 80);
+    }
+
+    #[test]
+    fn from_json_rejects_invalid_curves() {
+        assert!(Profile::from_json("bad", &json!({ "speeds": [] })).is_err());
+        assert!(Profile::from_json("bad", &json!({ "speeds": [50, 101] })).is_err());
+        assert!(Profile::from_json("bad", &json!({ "speeds": "fast" })).is_err());
+        assert!(Profile::from_json("bad", &json!({ "increments": [[70]] })).is_err());
+        assert!(Profile::from_json("bad", &json!({ "increments": [[70, 101]] })).is_err());
+        assert!(Profile::from_json("bad", &json!({ "increments": [[256, 5]] })).is_err());
+    }
+
+    #[test]
+    fn builtin_profiles_start_with_the_default() {
+        let names: Vec<String> = builtin_profiles().into_iter().map(|profile| profile.name).collect();
+        assert_eq!(names, ["default", "quiet", "performance"]);
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::backend::GpuReadout;
+use crate::profile::Profile;
+use crate::trace::split_trace_by_gpu;
+
+/// How a single fan curve would have behaved over a recorded trace.
+struct CurveStats {
+    /// Seconds spent at each commanded speed
+    time_at_speed: BTreeMap<u8, f64>,
+    speed_changes: u32,
+    max_speed: u8,
+    /// Time weighted average of the commanded speed
+    average_speed: f64,
+}
+
+/// Formats seconds as `1h 2m 3s`, `2m 3s` or `4.5s`, also used for the override durations of the client.
+pub fn format_duration(seconds: f64) -> String {
+    let whole = seconds.round() as u64;
+    if whole >= 3600 {
+        format!("{}h {}m {}s", whole / 3600, whole % 3600 / 60, whole % 60)
+    } else if whole >= 60 {
+        format!("{}m {}s", whole / 60, whole % 60)
+    } else {
+        format!("{:.1}s", seconds)
+    }
+}
+
+/// Works out how long each sample was in effect, i.e. the time until the next sample.
+///
+/// The last sample has nothing after it, so it is given the same duration as the one before it.
+fn sample_durations(samples: &[GpuReadout]) -> Vec<f64> {
+    let mut durations: Vec<f64> = samples.windows(2).map(|pair| (pair[1].timestamp - pair[0].timestamp).max(0.0)).collect();
+    durations.push(durations.last().copied().unwrap_or(0.0));
+    durations
+}
+
+/// Runs a profile over the samples of one GPU.
+fn curve_stats(samples: &[GpuReadout], durations: &[f64], profile: &Profile) -> CurveStats {
+    let mut stats = CurveStats { time_at_speed: BTreeMap::new(), speed_changes: 0, max_speed: 0, average_speed: 0.0 };
+    let mut previous_speed: Option<u8> = None;
+    let mut weighted_sum = 0.0;
+    let mut total_time = 0.0;
+
+    for (sample, &duration) in samples.iter().zip(durations) {
+        // Readings without a temperature give the curve nothing to work with
+        let Some(temp) = sample.metrics.temp else { continue };
+
+        let speed = profile.speed_for(temp as u8);
+        if previous_speed.is_some_and(|previous| previous != speed) {
+            stats.speed_changes += 1;
+        }
+        previous_speed = Some(speed);
+
+        *stats.time_at_speed.entry(speed).or_insert(0.0) += duration;
+        stats.max_speed = stats.max_speed.max(speed);
+        weighted_sum += speed as f64 * duration;
+        total_time += duration;
+    }
+
+    if total_time > 0.0 {
+        stats.average_speed = weighted_sum / total_time;
+    }
+
+    stats
+}
+
+/// Runs every profile over a recorded trace and describes how each curve would have behaved.
+///
+/// The report covers every GPU in the trace separately. Temperatures come straight from
+/// the recording, so the time spent above each threshold is reported once per GPU rather
+/// than per profile.
+pub fn compare_profiles(readouts: Vec<GpuReadout>, profiles: &[&Profile], thresholds: &[u8]) -> String {
+    let mut report = String::new();
+
+    for samples in split_trace_by_gpu(readouts) {
+        let durations = sample_durations(&samples);
+        let total_time: f64 = durations.iter().sum();
+        let percent_of_total = |seconds: f64| if total_time > 0.0 { seconds / total_time * 100.0 } else { 0.0 };
+
+        let _ = writeln!(report, "GPU {} ({}): {} samples over {}", samples[0].id, samples[0].label, samples.len(), format_duration(total_time));
+
+        let temps: Vec<f32> = samples.iter().filter_map(|sample| sample.metrics.temp).collect();
+        if let Some(max_temp) = temps.iter().copied().reduce(f32::max) {
+            let average_temp = temps.iter().sum::<f32>() / temps.len() as f32;
+            let _ = writeln!(report, "  Temperature: max {:.1}°C, average {:.1}°C", max_temp, average_temp);
+        }
+
+        for &threshold in thresholds {
+            let mut time_above = 0.0;
+            let mut excursions = 0;
+            let mut was_above = false;
+            for (sample, &duration) in samples.iter().zip(&durations) {
+                let above = sample.metrics.temp.is_some_and(|temp| temp > threshold as f32);
+                if above {
+                    time_above += duration;
+                    if !was_above {
+                        excursions += 1;
+                    }
+                }
+                was_above = above;
+            }
+            let _ = writeln!(report, "  Above {}°C: {} times, {} ({:.1}%)", threshold, excursions, format_duration(time_above), percent_of_total(time_above));
+        }
+
+        for profile in profiles {
+            let stats = curve_stats(&samples, &durations, profile);
+            let _ = writeln!(report);
+            let _ = writeln!(report, "  Profile \"{}\"", profile.name);
+            let _ = writeln!(report, "    Speed changes: {}", stats.speed_changes);
+            let _ = writeln!(report, "    Max speed: {}%", stats.max_speed);
+            let _ = writeln!(report, "    Average speed: {:.1}%", stats.average_speed);
+            let _ = writeln!(report, "    Time at speed:");
+            for (speed, seconds) in &stats.time_at_speed {
+                let _ = writeln!(report, "      {:>3}%: {} ({:.1}%)", speed, format_duration(*seconds), percent_of_total(*seconds));
+            }
+        }
+
+        let _ = writeln!(report);
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_duration_picks_the_largest_units() {
+        assert_eq!(format_duration(42.44), "42.4s");
+        assert_eq!(format_duration(598.0), "9m 58s");
+        assert_eq!(format_duration(7260.0), "2h 1m 0s");
+    }
+}
@@ -0,0 +1,31 @@
+use std::fs;
+use std::time::Instant;
+
+/// Smallest jump between the two clocks in seconds that counts as a suspend.
+const SUSPEND_THRESHOLD: f64 = 2.0;
+
+/// Notices when the system was suspended between two ticks.
+///
+/// `Instant` uses `CLOCK_MONOTONIC`, which stands still while suspended, while the
+/// uptime in `/proc/uptime` follows `CLOCK_BOOTTIME`, which keeps counting. The gap
+/// between the two only grows across a suspend.
+pub struct ResumeDetector {
+    start: Instant,
+    last_offset: Option<f64>,
+}
+
+impl ResumeDetector {
+    pub fn new() -> ResumeDetector {
+        ResumeDetector { start: Instant::now(), last_offset: None }
+    }
+
+    /// Returns how long the system was suspended if it resumed since the last call.
+    pub fn check(&mut self) -> Option<f64> {
+        let uptime: f64 = fs::read_to_string("/proc/uptime").ok()?.split_whitespace().next()?.parse().ok()?;
+        let offset = uptime - self.start.elapsed().as_secs_f64();
+
+        let suspended = self.last_offset.map(|last_offset| offset - last_offset).filter(|suspended| *suspended >= SUSPEND_THRESHOLD);
+        self.last_offset = Some(offset);
+        suspended
+    }
+}
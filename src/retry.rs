@@ -0,0 +1,128 @@
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::config::{positive_number_key, typed_key};
+use crate::error::Error;
+
+/// How often and how patiently transient backend errors are retried.
+///
+/// The delay starts at `initial_delay` and doubles after every failed attempt up to
+/// `max_delay`. Only errors for which [`Error::is_transient`] holds are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one
+    pub attempts: u32,
+    /// Seconds to wait before the first retry
+    pub initial_delay: f32,
+    /// Longest wait between two attempts in seconds
+    pub max_delay: f32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy { attempts: 3, initial_delay: 0.05, max_delay: 1.0 }
+    }
+}
+
+impl RetryPolicy {
+    /// Builds the policy from the `retry` object of the config file, a key left out keeps its default.
+    ///
+    /// ```json
+    /// "retry": { "attempts": 3, "initial_delay": 0.05, "max_delay": 1 }
+    /// ```
+    pub fn from_json(value: &Value) -> Result<RetryPolicy, String> {
+        let mut policy = RetryPolicy::default();
+
+        if let Some(attempts) = typed_key(value, "retry", "attempts", "must be a number of at least 1", |attempts| attempts.as_u64().filter(|attempts| *attempts >= 1))? {
+            policy.attempts = attempts as u32;
+        }
+        if let Some(initial_delay) = positive_number_key(value, "retry", "initial_delay")? {
+            policy.initial_delay = initial_delay;
+        }
+        if let Some(max_delay) = positive_number_key(value, "retry", "max_delay")? {
+            policy.max_delay = max_delay;
+        }
+
+        Ok(policy)
+    }
+
+    /// Runs `operation` until it succeeds, fails with an error that isn't transient or runs out of attempts.
+    pub fn run<T>(&self, mut operation: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(e) if e.is_transient() && attempt < self.attempts => {
+                    thread::sleep(Duration::from_secs_f32(delay));
+                    delay = (delay * 2.0).min(self.max_delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::time::Instant;
+
+    /// Runs `policy` on an operation that fails with `error` every time, returns when each attempt started.
+    fn attempts(policy: &RetryPolicy, error: impl Fn() -> Error) -> (Vec<Instant>, Error) {
+        let mut started = Vec::new();
+        let result: Result<(), Error> = policy.run(|| {
+            started.push(Instant::now());
+            Err(error())
+        });
+        (started, result.unwrap_err())
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy { attempts: 4, initial_delay: 0.02, max_delay: 0.03 };
+        let (started, _) = attempts(&policy, || Error::Transient(String::from("garbage from nvidia-smi")));
+
+        assert_eq!(started.len(), 4);
+        let delays: Vec<f32> = started.windows(2).map(|pair| (pair[1] - pair[0]).as_secs_f32()).collect();
+        for (delay, expected) in delays.iter().zip([0.02, 0.03, 0.03]) {
+            assert!(*delay >= expected && *delay < expected + 0.5, "waited {}s instead of {}s", delay, expected);
+        }
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        let policy = RetryPolicy { attempts: 3, initial_delay: 0.0, max_delay: 0.0 };
+
+        for error in [|| Error::Transient(String::from("busy")), || Error::io("Failed to read temp1_input", io::Error::from(io::ErrorKind::Interrupted))] {
+            let (started, error) = attempts(&policy, error);
+            assert_eq!(started.len(), 3);
+            assert!(error.is_transient() && !error.is_fatal());
+        }
+
+        for error in [|| Error::Permission(String::from("not root")), || Error::io("Failed to write pwm1", io::Error::from(io::ErrorKind::PermissionDenied))] {
+            let (started, error) = attempts(&policy, error);
+            assert_eq!(started.len(), 1);
+            assert!(error.is_fatal());
+        }
+
+        // A tool that timed out is neither retried nor a reason to stop
+        let (started, error) = attempts(&policy, || Error::io("nvidia-smi", io::Error::from(io::ErrorKind::TimedOut)));
+        assert_eq!(started.len(), 1);
+        assert!(!error.is_transient() && !error.is_fatal());
+    }
+
+    #[test]
+    fn success_stops_retrying() {
+        let policy = RetryPolicy { attempts: 5, initial_delay: 0.0, max_delay: 0.0 };
+        let mut calls = 0;
+        let result = policy.run(|| {
+            calls += 1;
+            if calls < 3 { Err(Error::Transient(String::from("busy"))) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 3);
+    }
+}
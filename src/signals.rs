@@ -0,0 +1,81 @@
+use std::io::{IsTerminal, Write};
+use std::panic;
+use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use signal_hook::consts::{SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use signal_hook::iterator::Signals;
+
+use crate::backend::{ControlledGpu, Restorer};
+use crate::error::Error;
+use crate::lock::release_gpu_locks;
+
+/// Exit status after a panic, the same one Rust uses for an unwinding panic
+const PANIC_EXIT_STATUS: i32 = 101;
+
+/// Runs every restorer, gives up the GPU locks and makes the cursor visible again.
+fn restore_all(restorers: &[Restorer]) {
+    for restorer in restorers {
+        restorer();
+    }
+    release_gpu_locks();
+
+    // Restore cursor visibility, without leaving escape codes in piped output such as JSON Lines
+    if std::io::stdout().is_terminal() {
+        print!("\x1B[?25h");
+        let _ = std::io::stdout().flush();
+    }
+}
+
+/// Hands fan control of every GPU back to the driver.
+fn restore_gpus(gpus: &[ControlledGpu]) {
+    let restorers: Vec<Restorer> = gpus.iter().map(|gpu| gpu.backend.restorer()).collect();
+    restore_all(&restorers);
+}
+
+/// Hands the fans of the GPUs taken over so far back to the driver when dropped.
+///
+/// Held by `run` from the first lock on, so returning early with an error or finishing a
+/// replay releases the locks and state files too. Signals and panics end the process
+/// without unwinding, [`setup_exit_handlers`] takes care of those.
+pub struct RestoreGuard(pub Arc<Mutex<Vec<ControlledGpu>>>);
+
+impl Drop for RestoreGuard {
+    fn drop(&mut self) {
+        restore_gpus(&self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+    }
+}
+
+/// Makes sure fan control is handed back to the driver however the program ends.
+///
+/// SIGINT, SIGTERM, SIGHUP and SIGQUIT restore the fans and exit with `128 + signal`
+/// like a shell would report it. A panic hook does the same and exits with status 101,
+/// which matters because release builds use `panic = 'abort'` and never unwind.
+///
+/// # Errors
+/// Fails if the signal handlers can't be installed, running without them could leave the fans stuck.
+pub fn setup_exit_handlers(gpus: Arc<Mutex<Vec<ControlledGpu>>>) -> Result<(), Error> {
+    let restorers: Vec<Restorer> = gpus.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter().map(|gpu| gpu.backend.restorer()).collect();
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP, SIGQUIT]).map_err(|e| Error::io("Error setting signal handlers", e))?;
+    let signal_restorers = restorers.clone();
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            // Wait for the control loop to finish its tick so it can't write a speed after we restored
+            let _gpus = gpus.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            restore_all(&signal_restorers);
+            exit(128 + signal);
+        }
+    });
+
+    // The panicking thread may be holding the GPU lock, so only the restorers are used here
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_all(&restorers);
+        default_hook(info);
+        exit(PANIC_EXIT_STATUS);
+    }));
+
+    Ok(())
+}
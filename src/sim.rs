@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::backend::{GpuBackend, GpuMetrics, Restorer};
+use crate::error::Error;
+
+/// Power drawn by the simulated GPU at 0% load (in watts)
+const IDLE_POWER: f32 = 20.0;
+/// Power drawn by the simulated GPU at 100% load (in watts)
+const MAX_POWER: f32 = 300.0;
+/// Heat needed to raise the GPU and heatsink by 1°C (in joules per °C)
+const HEAT_CAPACITY: f32 = 250.0;
+/// Heat shed to the ambient air with the fans stopped (in watts per °C)
+const PASSIVE_CONDUCTANCE: f32 = 1.0;
+/// Additional heat shed with the fans at 100% (in watts per °C)
+const FAN_CONDUCTANCE: f32 = 6.0;
+/// How quickly the fans spin up or down towards the requested speed (in seconds)
+const FAN_TIME_CONSTANT: f32 = 2.0;
+/// Fan speed the simulated driver uses while nobody controls the fans
+const AUTO_FAN_SPEED: f32 = 30.0;
+/// RPM of the simulated fans at 100%
+const MAX_RPM: f32 = 3300.0;
+/// Largest simulated time step used when integrating the model (in seconds)
+const MAX_STEP: f32 = 0.05;
+
+/// One segment of a load profile, the load is a fraction between 0.0 and 1.0.
+#[derive(Clone, Copy, Debug)]
+pub struct LoadStep {
+    pub load: f32,
+    pub seconds: f32,
+}
+
+/// Parses a load profile, either one of the named profiles or a custom sequence.
+///
+/// Named profiles are `idle`, `gaming`, `render` and `burst`. A custom profile is a
+/// comma separated list of `load%:seconds` pairs, e.g. `100:30,10:60`, which is
+/// repeated once it reaches the end.
+pub fn parse_load_profile(profile: &str) -> Result<Vec<LoadStep>, String> {
+    let steps: &[(f32, f32)] = match profile {
+        "idle" => &[(0.05, 60.0)],
+        "gaming" => &[(0.7, 20.0), (0.9, 10.0), (0.6, 15.0), (0.85, 15.0)],
+        "render" => &[(1.0, 60.0)],
+        "burst" => &[(1.0, 30.0), (0.1, 60.0)],
+        custom => {
+            let mut steps = Vec::new();
+            for pair in custom.split(',') {
+                let (load, seconds) = pair.split_once(':').ok_or_else(|| format!("Invalid load step \"{}\", expected load%:seconds", pair))?;
+                let load: f32 = load.trim().parse().map_err(|_| format!("Invalid load \"{}\" in load step \"{}\"", load, pair))?;
+                let seconds: f32 = seconds.trim().parse().map_err(|_| format!("Invalid duration \"{}\" in load step \"{}\"", seconds, pair))?;
+                if !(0.0..=100.0).contains(&load) || seconds <= 0.0 {
+                    return Err(format!("Load step \"{}\" must have a load of 0-100% and a positive duration", pair));
+                }
+                steps.push(LoadStep { load: load / 100.0, seconds });
+            }
+            return Ok(steps);
+        }
+    };
+
+    Ok(steps.iter().map(|&(load, seconds)| LoadStep { load, seconds }).collect())
+}
+
+/// Simulated GPU modelled as a single thermal mass.
+///
+/// The GPU heats up according to the current step of its load profile and sheds heat
+/// to the ambient air, more effectively the faster the fans spin. The fans follow the
+/// speeds written by the control loop with a small delay like real fans do.
+pub struct SimulatedGpu {
+    ambient: f32,
+    cooling: f32,
+    time_scale: f32,
+    load_profile: Vec<LoadStep>,
+    temp: f32,
+    load: f32,
+    fan_speed: f32,
+    target_speed: f32,
+    /// Power limit in watts set through an emergency action
+    power_limit: Option<f32>,
+    sim_time: f32,
+    last_update: Instant,
+}
+
+impl SimulatedGpu {
+    /// Creates a simulated GPU sitting at ambient temperature.
+    ///
+    /// `cooling` scales how effective the fans are (1.0 is a typical card) and
+    /// `time_scale` makes simulated time pass faster than wall clock time.
+    pub fn new(load_profile: Vec<LoadStep>, ambient: f32, cooling: f32, time_scale: f32) -> SimulatedGpu {
+        SimulatedGpu {
+            ambient,
+            cooling,
+            time_scale,
+            load_profile,
+            temp: ambient,
+            load: 0.0,
+            fan_speed: AUTO_FAN_SPEED,
+            target_speed: AUTO_FAN_SPEED,
+            power_limit: None,
+            sim_time: 0.0,
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Looks up the load at the given point in simulated time, repeating the profile.
+    fn load_at(&self, time: f32) -> f32 {
+        let total: f32 = self.load_profile.iter().map(|step| step.seconds).sum();
+        if total <= 0.0 {
+            return 0.0;
+        }
+
+        let mut offset = time % total;
+        for step in &self.load_profile {
+            if offset < step.seconds {
+                return step.load;
+            }
+            offset -= step.seconds;
+        }
+        self.load_profile.last().map_or(0.0, |step| step.load)
+    }
+
+    /// Advances the model by the wall clock time passed since the last update.
+    fn advance(&mut self) {
+        let elapsed = self.last_update.elapsed().as_secs_f32() * self.time_scale;
+        self.last_update = Instant::now();
+
+        let steps = (elapsed / MAX_STEP).ceil().clamp(1.0, 10_000.0);
+        let dt = elapsed / steps;
+
+        for _ in 0..steps as u32 {
+            self.load = self.load_at(self.sim_time);
+
+            // Fans approach the requested speed exponentially
+            self.fan_speed += (self.target_speed - self.fan_speed) * (dt / FAN_TIME_CONSTANT).min(1.0);
+
+            // Airflow improves heat transfer less than linearly
+            let conductance = PASSIVE_CONDUCTANCE + FAN_CONDUCTANCE * self.cooling * (self.fan_speed / 100.0).powf(0.8);
+            let power = (IDLE_POWER + (MAX_POWER - IDLE_POWER) * self.load).min(self.power_limit.unwrap_or(MAX_POWER));
+
+            self.temp += (power - conductance * (self.temp - self.ambient)) / HEAT_CAPACITY * dt;
+            self.sim_time += dt;
+        }
+    }
+}
+
+impl GpuBackend for SimulatedGpu {
+    fn id(&self) -> String {
+        String::from("sim-0")
+    }
+
+    fn label(&self) -> String {
+        format!("Simulated GPU ({:.0}% load)", self.load * 100.0)
+    }
+
+    fn read_metrics(&mut self) -> Result<GpuMetrics, Error> {
+        self.advance();
+
+        Ok(GpuMetrics {
+            temp: Some(self.temp),
+            junction_temp: Some(self.temp + 2.0 + self.load * 18.0),
+            memory_temp: Some(self.ambient + (self.temp - self.ambient) * 0.85 + 4.0),
+            fan_rpm: Some(self.fan_speed / 100.0 * MAX_RPM),
+            fan_min_rpm: Some(0.0),
+            fan_max_rpm: Some(MAX_RPM),
+            fan_speed: Some(self.fan_speed),
+            fan_duty: None,
+        })
+    }
+
+    fn set_fan_speed(&mut self, speed: u8) -> Result<(), Error> {
+        self.target_speed = speed.min(100) as f32;
+        Ok(())
+    }
+
+    fn set_power_limit(&mut self, watts: Option<u32>) -> Result<(), Error> {
+        self.power_limit = watts.map(|watts| watts as f32);
+        Ok(())
+    }
+
+    /// Nothing to hand back, the simulation ends with the program.
+    fn restorer(&self) -> Restorer {
+        Arc::new(|| {})
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Instant;
+
+use serde_json::Value;
+
+use crate::config::positive_number_key;
+use crate::error::Error;
+use crate::logging::info;
+use crate::sleep;
+
+/// How long to wait for the GPU driver at startup.
+///
+/// Services started early during boot can run before the NVIDIA driver is loaded or
+/// the amdgpu hwmon directory exists. Discovery is retried, starting `initial_delay`
+/// seconds apart and doubling up to `max_delay`, until `timeout` seconds have passed.
+/// A timeout of 0 gives up on the first failure.
+#[derive(Clone, Debug)]
+pub struct StartupPolicy {
+    pub timeout: f32,
+    pub initial_delay: f32,
+    pub max_delay: f32,
+}
+
+impl Default for StartupPolicy {
+    fn default() -> StartupPolicy {
+        StartupPolicy { timeout: 0.0, initial_delay: 1.0, max_delay: 10.0 }
+    }
+}
+
+impl StartupPolicy {
+    /// Reads how long to wait for the driver from the `startup` object of the config file.
+    ///
+    /// ```json
+    /// "startup": { "timeout": 120, "initial_delay": 1, "max_delay": 10 }
+    /// ```
+    pub fn from_json(value: &Value) -> Result<StartupPolicy, String> {
+        let mut policy = StartupPolicy::default();
+
+        if let Some(timeout) = positive_number_key(value, "startup", "timeout")? {
+            policy.timeout = timeout;
+        }
+        if let Some(initial_delay) = positive_number_key(value, "startup", "initial_delay")? {
+            policy.initial_delay = initial_delay;
+        }
+        if let Some(max_delay) = positive_number_key(value, "startup", "max_delay")? {
+            policy.max_delay = max_delay;
+        }
+
+        Ok(policy)
+    }
+
+    /// Runs `discover` until it succeeds or the timeout expires.
+    ///
+    /// # Errors
+    /// Missing privileges fail right away, anything else once the timeout expired, saying what was waited for.
+    pub fn wait_for<T>(&self, what: &str, mut discover: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let started = Instant::now();
+        let mut delay = self.initial_delay;
+
+        loop {
+            let e = match discover() {
+                Ok(value) => return Ok(value),
+                Err(e @ Error::Permission(_)) => return Err(e),
+                Err(e) => e,
+            };
+
+            let waited = started.elapsed().as_secs_f32();
+            if waited >= self.timeout {
+                if self.timeout > 0.0 {
+                    return Err(Error::Unsupported(format!("Gave up waiting for {} after {:.0}s: {}", what, waited, e)));
+                }
+                return Err(e);
+            }
+
+            // The last attempt happens right when the timeout expires
+            let wait = delay.min(self.timeout - waited);
+            info!("Waiting for {}: {}, retrying in {:.1}s", what, e, wait);
+            sleep(wait);
+            delay = (delay * 2.0).min(self.max_delay);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::PathBuf;
+
+use serde_json::Value;
+
+use crate::dry_run::is_dry_run;
+use crate::logging::error;
+
+/// Where the pre-launch fan state of every controlled GPU is kept while we run.
+///
+/// `/run` is cleared on reboot, which is exactly when a leftover snapshot stops being meaningful.
+pub const STATE_DIR: &str = "/run/rust-gpu-fan-control";
+
+/// Path of the state file of a GPU, see [`crate::backend::GpuBackend::id`].
+fn state_path(gpu_id: &str) -> PathBuf {
+    PathBuf::from(STATE_DIR).join(format!("{}.json", gpu_id.replace('/', "_")))
+}
+
+/// Returns the fan state to restore on exit and persists it for the next start.
+///
+/// If a state file is still around, the previous run ended without restoring the fans
+/// (e.g. it was killed), so the state it recorded is the real pre-launch state and is
+/// returned instead of `current`. Dry runs leave the state directory alone since they
+/// never touch the fans.
+///
+/// # Returns
+/// - The state to restore on exit
+/// - Whether it was recovered from a previous run
+pub fn load_or_save_original_state(gpu_id: &str, current: Value) -> (Value, bool) {
+    if is_dry_run() {
+        return (current, false);
+    }
+
+    let path = state_path(gpu_id);
+
+    if let Some(saved) = fs::read_to_string(&path).ok().and_then(|content| serde_json::from_str::<Value>(&content).ok()) {
+        return (saved, true);
+    }
+
+    if let Err(e) = fs::create_dir_all(STATE_DIR).and_then(|_| fs::write(&path, current.to_string())) {
+        error!("Failed to save the original fan state to {}: {}", path.display(), e);
+    }
+    (current, false)
+}
+
+/// Removes the state file once the original fan state has been restored.
+pub fn clear_original_state(gpu_id: &str) {
+    if is_dry_run() {
+        return;
+    }
+    let _ = fs::remove_file(state_path(gpu_id));
+}
@@ -0,0 +1,251 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::backend::{GpuBackend, GpuMetrics, GpuReadout, Restorer, SpeedReason};
+use crate::error::Error;
+use crate::logging::error;
+
+/// Current Unix time in seconds, used to timestamp readouts.
+pub fn unix_timestamp() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0.0, |duration| duration.as_secs_f64())
+}
+
+/// Rounds a sensor value to two decimals so `f32` noise does not end up in the JSON.
+fn round_metric(value: Option<f32>) -> Option<f64> {
+    value.map(|value| (value as f64 * 100.0).round() / 100.0)
+}
+
+/// Converts the sensor values of one GPU to JSON, unreported sensors become `null`.
+///
+/// This is the part of a trace line that doesn't need the control loop, so `bar` can
+/// report GPUs it sampled itself in the same shape.
+pub fn metrics_to_json(id: &str, label: &str, metrics: &GpuMetrics) -> Value {
+    json!({
+        "gpu": id,
+        "label": label,
+        "temp": round_metric(metrics.temp),
+        "junction_temp": round_metric(metrics.junction_temp),
+        "memory_temp": round_metric(metrics.memory_temp),
+        "fan_rpm": round_metric(metrics.fan_rpm),
+        "fan_min_rpm": round_metric(metrics.fan_min_rpm),
+        "fan_max_rpm": round_metric(metrics.fan_max_rpm),
+        "fan_speed": round_metric(metrics.fan_speed),
+        "fan_duty": round_metric(metrics.fan_duty),
+    })
+}
+
+/// Converts a readout to the JSON object stored on one line of a trace file.
+pub fn readout_to_json(readout: &GpuReadout) -> Value {
+    let mut value = metrics_to_json(&readout.id, &readout.label, &readout.metrics);
+    value["timestamp"] = json!(readout.timestamp);
+    value["speed"] = json!(readout.speed_output);
+    value["wrote"] = json!(readout.changed);
+    value["reason"] = json!(readout.reason.as_str());
+    value["failsafe"] = json!(readout.failsafe);
+    value["emergency"] = json!(readout.emergency);
+    value
+}
+
+/// Parses one line of a trace file back into a readout.
+fn readout_from_json(value: &Value) -> Option<GpuReadout> {
+    let float = |key: &str| value.get(key).and_then(Value::as_f64).map(|number| number as f32);
+
+    let metrics = GpuMetrics {
+        temp: float("temp"),
+        junction_temp: float("junction_temp"),
+        memory_temp: float("memory_temp"),
+        fan_rpm: float("fan_rpm"),
+        fan_min_rpm: float("fan_min_rpm"),
+        fan_max_rpm: float("fan_max_rpm"),
+        fan_speed: float("fan_speed"),
+        fan_duty: float("fan_duty"),
+    };
+
+    Some(GpuReadout {
+        timestamp: value.get("timestamp")?.as_f64()?,
+        id: value.get("gpu")?.as_str()?.to_string(),
+        label: value.get("label").and_then(Value::as_str).unwrap_or_default().to_string(),
+        temp: metrics.temp.map_or(0, |temp| temp as u8),
+        metrics,
+        speed_output: value.get("speed").and_then(Value::as_u64).unwrap_or(0).min(100) as u8,
+        changed: value.get("wrote").and_then(Value::as_bool).unwrap_or(false),
+        reason: value.get("reason").and_then(Value::as_str).and_then(SpeedReason::parse).unwrap_or(SpeedReason::Curve),
+        failsafe: value.get("failsafe").and_then(Value::as_str).map(String::from),
+        emergency: value.get("emergency").and_then(Value::as_array).map(|actions| actions.iter().filter_map(Value::as_str).map(String::from).collect()).unwrap_or_default(),
+    })
+}
+
+/// Appends every tick's readouts to a JSON Lines trace file.
+pub struct TraceRecorder {
+    writer: LineWriter<File>,
+}
+
+impl TraceRecorder {
+    pub fn create(path: &str) -> Result<TraceRecorder, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| Error::io(format!("Failed to open trace file {}", path), e))?;
+        Ok(TraceRecorder { writer: LineWriter::new(file) })
+    }
+
+    /// Writes a single readout as one line, lines are flushed as soon as they are complete.
+    pub fn record(&mut self, readout: &GpuReadout) {
+        if let Err(e) = writeln!(self.writer, "{}", readout_to_json(readout)) {
+            error!("Failed to write to trace file: {}", e);
+        }
+    }
+}
+
+/// Loads every readout from a trace file in the order it was recorded.
+pub fn load_trace(path: &str) -> Result<Vec<GpuReadout>, Error> {
+    let file = File::open(path).map_err(|e| Error::io(format!("Failed to open trace file {}", path), e))?;
+
+    let mut readouts = Vec::new();
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| Error::io(format!("Failed to read trace file {}", path), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let readout = serde_json::from_str::<Value>(&line).ok().as_ref().and_then(readout_from_json);
+        match readout {
+            Some(readout) => readouts.push(readout),
+            None => return Err(Error::Config(format!("Invalid trace entry on line {} of {}", line_number + 1, path))),
+        }
+    }
+
+    if readouts.is_empty() {
+        return Err(Error::Config(format!("Trace file {} contains no readouts", path)));
+    }
+
+    Ok(readouts)
+}
+
+/// Splits a trace into one list of readouts per recorded GPU, keeping the recorded order.
+pub fn split_trace_by_gpu(readouts: Vec<GpuReadout>) -> Vec<Vec<GpuReadout>> {
+    let mut per_gpu: Vec<Vec<GpuReadout>> = Vec::new();
+    for readout in readouts {
+        match per_gpu.iter_mut().find(|samples| samples[0].id == readout.id) {
+            Some(samples) => samples.push(readout),
+            None => per_gpu.push(vec![readout]),
+        }
+    }
+    per_gpu
+}
+
+/// Backend that plays back the sensor values of a recorded trace.
+///
+/// Readings follow the recorded timing (optionally sped up) so the current fan curve
+/// sees the same temperatures at the same pace as the original run. Fan writes only
+/// go to the TUI, no hardware is touched.
+pub struct ReplayGpu {
+    id: String,
+    label: String,
+    samples: Vec<GpuReadout>,
+    position: usize,
+    time_scale: f64,
+    start: Instant,
+}
+
+impl ReplayGpu {
+    /// Creates a replay of the samples of a single GPU, see [`split_trace_by_gpu`].
+    pub fn new(samples: Vec<GpuReadout>, time_scale: f64) -> ReplayGpu {
+        let id = samples.first().map(|sample| sample.id.clone()).unwrap_or_default();
+        let label = samples.first().map(|sample| sample.label.clone()).filter(|label| !label.is_empty()).unwrap_or_else(|| id.clone());
+        ReplayGpu { id, label: format!("Replay of {}", label), samples, position: 0, time_scale, start: Instant::now() }
+    }
+
+    /// Seconds into the recording that the replay has reached.
+    fn replay_offset(&self) -> f64 {
+        self.start.elapsed().as_secs_f64() * self.time_scale
+    }
+}
+
+impl GpuBackend for ReplayGpu {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn label(&self) -> String {
+        self.label.clone()
+    }
+
+    fn read_metrics(&mut self) -> Result<GpuMetrics, Error> {
+        let first_timestamp = self.samples.first().ok_or_else(|| Error::Config(String::from("Replayed trace is empty")))?.timestamp;
+        let offset = self.replay_offset();
+
+        while self.position + 1 < self.samples.len() && self.samples[self.position + 1].timestamp - first_timestamp <= offset {
+            self.position += 1;
+        }
+
+        Ok(self.samples[self.position].metrics)
+    }
+
+    fn set_fan_speed(&mut self, _speed: u8) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn restorer(&self) -> Restorer {
+        Arc::new(|| {})
+    }
+
+    /// The recorded fans followed whatever curve was active while recording.
+    fn follows_writes(&self) -> bool {
+        false
+    }
+
+    fn finished(&self) -> bool {
+        match (self.samples.first(), self.samples.last()) {
+            (Some(first), Some(last)) => self.position + 1 >= self.samples.len() && self.replay_offset() >= last.timestamp - first.timestamp,
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn readout(id: &str, timestamp: f64, temp: f32) -> GpuReadout {
+        let metrics = GpuMetrics { temp: Some(temp), junction_temp: Some(temp + 12.5), memory_temp: None, fan_rpm: Some(1650.0), fan_min_rpm: Some(0.0), fan_max_rpm: Some(3300.0), fan_speed: Some(50.0), fan_duty: Some(49.8) };
+        GpuReadout { timestamp, id: id.to_string(), label: format!("GPU {}", id), metrics, temp: temp as u8, speed_output: 100, changed: true, reason: SpeedReason::Failsafe, failsafe: Some(String::from("reading is 6.0s old")), emergency: vec![String::from("power_limit")] }
+    }
+
+    #[test]
+    fn readouts_survive_a_round_trip_through_a_trace_file() {
+        let path = std::env::temp_dir().join(format!("rust-gpu-fan-control-trace-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let written = [readout("nvidia-0", 1_714_570_620.25, 71.5), readout("amdgpu-0000:03:00.0", 1_714_570_620.5, 64.0), readout("nvidia-0", 1_714_570_621.25, 72.0)];
+
+        let mut recorder = TraceRecorder::create(path.to_str().unwrap()).unwrap();
+        for readout in &written {
+            recorder.record(readout);
+        }
+        drop(recorder);
+        let loaded = load_trace(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), written.len());
+        for (loaded, written) in loaded.iter().zip(&written) {
+            assert_eq!(readout_to_json(loaded), readout_to_json(written));
+            assert_eq!(loaded.metrics, written.metrics);
+            assert_eq!(loaded.temp, written.temp);
+        }
+
+        let per_gpu = split_trace_by_gpu(loaded);
+        assert_eq!(per_gpu.iter().map(Vec::len).collect::<Vec<_>>(), [2, 1]);
+    }
+
+    #[test]
+    fn readout_from_json_needs_a_timestamp_and_gpu() {
+        assert!(readout_from_json(&json!({ "gpu": "sim-0", "temp": 50.0 })).is_none());
+        assert!(readout_from_json(&json!({ "timestamp": 1.0, "temp": 50.0 })).is_none());
+
+        let minimal = readout_from_json(&json!({ "timestamp": 1.0, "gpu": "sim-0" })).unwrap();
+        assert_eq!(minimal.reason, SpeedReason::Curve);
+        assert_eq!(minimal.metrics, GpuMetrics::default());
+    }
+}
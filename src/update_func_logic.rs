@@ -7,13 +7,16 @@ use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::header;
 use tokio::runtime::Builder;
 
+use crate::error::Error;
+use crate::logging::error;
+
 #[derive(Debug)]
 pub struct Checksum {
     pub key: String,
     pub value: String,
 }
 
-pub fn is_current_version_older(repo_url: &str, compiled_version: &str) -> Result<(bool, String), Box<dyn std::error::Error>> {
+pub fn is_current_version_older(repo_url: &str, compiled_version: &str) -> Result<(bool, String), Error> {
     // Fetch the Cargo.toml file from the repository
     let cargo_toml_url = format!("{}/raw/main/Cargo.toml", repo_url);
     let cargo_toml_content = reqwest::blocking::get(cargo_toml_url)?.text()?;
@@ -24,7 +27,7 @@ pub fn is_current_version_older(repo_url: &str, compiled_version: &str) -> Resul
         .find(|line| line.starts_with("version"))
         .and_then(|line| line.split('=').nth(1))
         .and_then(|version| version.trim().strip_prefix('"').and_then(|v| v.strip_suffix('"')))
-        .ok_or("Version not found in Cargo.toml")?;
+        .ok_or_else(|| Error::Update(String::from("Version not found in Cargo.toml")))?;
 
     // Compare the versions
     let is_older = compare_versions(compiled_version, repo_version)?;
@@ -34,9 +37,10 @@ pub fn is_current_version_older(repo_url: &str, compiled_version: &str) -> Resul
 
 /// Compares two version strings to determine if the repository version is newer than the compiled
 /// version.
-fn compare_versions(compiled_version: &str, repo_version: &str) -> Result<bool, Box<dyn std::error::Error>> {
-    let compiled_parts: Vec<u64> = compiled_version.split('.').map(|part| part.parse::<u64>()).collect::<Result<_, _>>()?;
-    let repo_parts: Vec<u64> = repo_version.split('.').map(|part| part.parse::<u64>()).collect::<Result<_, _>>()?;
+fn compare_versions(compiled_version: &str, repo_version: &str) -> Result<bool, Error> {
+    let parse = |version: &str| version.split('.').map(|part| part.parse::<u64>()).collect::<Result<Vec<u64>, _>>().map_err(|e| Error::Update(format!("Invalid version \"{}\": {}", version, e)));
+    let compiled_parts = parse(compiled_version)?;
+    let repo_parts = parse(repo_version)?;
 
     let max_length = std::cmp::max(compiled_parts.len(), repo_parts.len());
 
@@ -57,46 +61,35 @@ fn compare_versions(compiled_version: &str, repo_version: &str) -> Result<bool,
 
 pub fn update_func_commit(file_path: &Path, file_path_tmp: &Path) {
     if let Err(e) = std::fs::remove_file(file_path) {
-        eprintln!("Failed to delete old file: {}", e);
+        error!("Failed to delete old file: {}", e);
     } else {
         match rename(file_path_tmp, file_path) {
             Ok(_) => {}
-            Err(e) => eprintln!("Failed to rename file: {}", e),
+            Err(e) => error!("Failed to rename file: {}", e),
         }
     }
 }
 
-pub fn update_func(binary_name: &str, file_path_tmp: &Path) -> Vec<Checksum> {
+pub fn update_func(binary_name: &str, file_path_tmp: &Path) -> Result<Vec<Checksum>, Error> {
     let runtime = Builder::new_current_thread()
         .thread_name("update_func_runtime")
         .enable_all() // Enable all features (like timeouts, blocking, etc.)
         .build()
-        .unwrap();
+        .map_err(|e| Error::io("Failed to start the update runtime", e))?;
 
-    let checksums_result = runtime.block_on(async {
+    runtime.block_on(async {
         let release_url = "https://api.github.com/repos/UnknownSuperficialNight/nvidia-fan-control/releases/latest";
 
         let client = reqwest::Client::new();
 
-        let response = client.get(release_url).header(header::USER_AGENT, "Nvidia Fanctrl").send().await.unwrap();
+        let response = client.get(release_url).header(header::USER_AGENT, "Nvidia Fanctrl").send().await?;
 
-        let response_text = response.text().await.unwrap();
+        let response_text = response.text().await?;
 
-        let json_result = serde_json::from_str::<serde_json::Value>(&response_text).unwrap();
+        let json_result = serde_json::from_str::<serde_json::Value>(&response_text).map_err(|e| Error::Update(format!("Invalid release information: {}", e)))?;
 
-        let assets = json_result["assets"].as_array().unwrap();
-        let asset = assets
-            .iter()
-            .find(|a| {
-                let name = a["name"].as_str().unwrap();
-                name == binary_name
-            })
-            .or_else(|| {
-                assets.iter().find(|a| {
-                    let name = a["name"].as_str().unwrap();
-                    name == "Rust-gpu-fan-control-static"
-                })
-            });
+        let assets = json_result["assets"].as_array().ok_or_else(|| Error::Update(String::from("Release information lists no assets")))?;
+        let asset = assets.iter().find(|a| a["name"].as_str() == Some(binary_name)).or_else(|| assets.iter().find(|a| a["name"].as_str() == Some("Rust-gpu-fan-control-static")));
 
         // Find the checksums.json asset
         let checksums_asset = assets.iter().find(|a| {
@@ -108,13 +101,13 @@ pub fn update_func(binary_name: &str, file_path_tmp: &Path) -> Vec<Checksum> {
         if let Some(asset) = checksums_asset {
             let checksums_url = asset["browser_download_url"].as_str().unwrap_or_default();
 
-            let response = client.get(checksums_url).header(header::USER_AGENT, "Nvidia Fanctrl").send().await.unwrap();
+            let response = client.get(checksums_url).header(header::USER_AGENT, "Nvidia Fanctrl").send().await?;
 
             // Extract the response body as bytes
-            let response_bytes = response.bytes().await.unwrap();
+            let response_bytes = response.bytes().await?;
 
             // Parse checksums.json as JSON
-            let checksums_json: serde_json::Value = serde_json::from_slice(&response_bytes).unwrap();
+            let checksums_json: serde_json::Value = serde_json::from_slice(&response_bytes).map_err(|e| Error::Update(format!("Invalid checksums.json: {}", e)))?;
 
             if let Some(object) = checksums_json.as_object() {
                 for (key, value) in object {
@@ -125,28 +118,27 @@ pub fn update_func(binary_name: &str, file_path_tmp: &Path) -> Vec<Checksum> {
         }
 
         if let Some(asset) = asset {
-            let download_url = asset["browser_download_url"].as_str().unwrap();
+            let download_url = asset["browser_download_url"].as_str().ok_or_else(|| Error::Update(String::from("Release asset has no download URL")))?;
 
-            let mut file = File::create(file_path_tmp).expect("Failed to create file");
+            let mut file = File::create(file_path_tmp).map_err(|e| Error::io("Failed to create file", e))?;
 
-            let mut response = client.get(download_url).send().await.unwrap();
-            let content_length = response.content_length().unwrap();
+            let mut response = client.get(download_url).send().await?;
+            let content_length = response.content_length().unwrap_or(0);
             let mut downloaded = 0u64;
             let pb = ProgressBar::new(content_length);
             pb.set_style(
                 ProgressStyle::default_bar().template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} MiB ({eta})").expect("Failed to create ProgressStyle object").progress_chars("##-"),
             );
 
-            while let Some(chunk) = response.chunk().await.unwrap() {
-                file.write_all(&chunk).unwrap();
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).map_err(|e| Error::io("Failed to write the downloaded binary", e))?;
                 downloaded += chunk.len() as u64;
                 pb.set_position(downloaded);
             }
             let permissions = Permissions::from_mode(0o755); // Sets the permission to rwxr-xr-x
-            file.set_permissions(permissions).unwrap();
+            file.set_permissions(permissions).map_err(|e| Error::io("Failed to make the downloaded binary executable", e))?;
         }
 
-        checksums
-    });
-    checksums_result
+        Ok(checksums)
+    })
 }